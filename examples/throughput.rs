@@ -0,0 +1,67 @@
+//! Measure the throughput of the record layer on loopback, run with `cargo run --release --example throughput`.
+use futures::future;
+use kyberauth::*;
+use safe_pqc_kyber::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Instant;
+const MESSAGE: usize = 1024 * 1024;
+const ROUNDS: usize = 256;
+fn report(name: &str, start: Instant) {
+    let elapsed = start.elapsed().as_secs_f64();
+    let size = (MESSAGE * ROUNDS) as f64 / (1024.0 * 1024.0);
+    println!("{:<36} {:>8.1} MiB/s", name, size / elapsed);
+}
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut rng = rand::thread_rng();
+    let keys = keypair(&mut rng);
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 43100);
+    let listener = server::startlistener(addr).await.unwrap();
+    //The keys are generated by each run, they are not checked against known_hosts
+    let config = client::ClientConfig::new().hostkeypolicy(client::HostKeyPolicy::AcceptAny);
+    let (sender, receiver) =
+        future::join(server::listener(&keys, listener, true), client::connecterwith(&keys, addr, &config)).await;
+    let (mut sender, mut receiver) = (sender.unwrap(), receiver.unwrap());
+    let message = vec![0x42u8; MESSAGE];
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let records = sender.encryptdata(&message).unwrap();
+        receiver.decryptdata(&records).unwrap();
+    }
+    report("encryptdata/decryptdata", start);
+    let mut buffer: Vec<u8> = Vec::with_capacity(sender.encryptedsize(MESSAGE));
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        buffer.clear();
+        buffer.extend_from_slice(&message);
+        sender.encrypt_in_place(&mut buffer).unwrap();
+        receiver.decrypt_in_place(&mut buffer).unwrap();
+    }
+    report("encrypt_in_place/decrypt_in_place", start);
+    let start = Instant::now();
+    let send = async {
+        for _ in 0..ROUNDS {
+            sender.senddata(&message).await.unwrap();
+        }
+    };
+    let receive = async {
+        for _ in 0..ROUNDS {
+            receiver.receivedata().await.unwrap().unwrap();
+        }
+    };
+    future::join(send, receive).await;
+    report("senddata/receivedata", start);
+    let start = Instant::now();
+    let send = async {
+        for _ in 0..ROUNDS {
+            sender.senddata(&message).await.unwrap();
+        }
+    };
+    let receive = async {
+        for _ in 0..ROUNDS {
+            receiver.receive_into(&mut buffer).await.unwrap().unwrap();
+        }
+    };
+    future::join(send, receive).await;
+    report("senddata/receive_into", start);
+}
@@ -1,48 +1,363 @@
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{AeadInPlace, KeyInit},
     Aes256Gcm,
-    Key // Or `Aes128Gcm`use kyberauth::printkeystofile;
 };
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::ChaCha20Poly1305;
 use hex;
+use crate::early::EarlyData;
+use crate::error::KyberauthError;
+use crate::hello::{Extension, Negotiated};
+use crate::kdf::{Exporter, Role, TrafficSecrets, HASHSIZE, IVSIZE};
+use crate::key::Kem;
+use crate::ticket::{self, Ticket, TicketKeys};
+use crate::split::{EncryptedReader, EncryptedWriter};
 use safe_pqc_kyber::*;
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::future::Future;
 use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::ops::Range;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use zeroize::Zeroize;
-const NONCESIZE: usize = 96 / 8;
-const MAXSIZE: usize = 10000;
+use tokio::time::Instant;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+const NONCESIZE: usize = IVSIZE;
+const TAGSIZE: usize = 128 / 8;
+const SEQSIZE: usize = 64 / 8;
+pub(crate) const HEADERSIZE: usize = 4;
+/// Maximum plaintext carried by a single record, bigger messages are split in several records.
+pub(crate) const CHUNKSIZE: usize = 10000;
+//Size of the data length in a padded record
+const PADHEADER: usize = 2;
+/// Maximum size of a record without its length header.
+pub(crate) const RECORDSIZE: usize = CHUNKSIZE + 1 + PADHEADER + SEQSIZE + TAGSIZE;
+/// Default maximum size of a whole message, see [`Connection::setmaxsize`].
+pub const DEFAULTMAXSIZE: usize = 64 * 1024 * 1024;
+//Flag set on the last record of a message
+pub(crate) const FLAG_FINAL: u8 = 1;
+//Flag set on the last record encrypted with the current key, the next one uses the updated key
+const FLAG_KEYUPDATE: u8 = 2;
+//Flag of the close notify record, the peer will not send anything after it
+pub(crate) const FLAG_CLOSE: u8 = 4;
+//Flag of a heartbeat record, it carries no data and is skipped by the receiver
+pub(crate) const FLAG_HEARTBEAT: u8 = 8;
+//Flag of a padded record, the data length follows the flags and zeros follow the data
+const FLAG_PADDED: u8 = 16;
+//Flag of a resumption ticket issued by the server, kept by the client
+pub(crate) const FLAG_TICKET: u8 = 32;
+//Records generated by the library, they never carry associated data of the caller
+const FLAG_CONTROL: u8 = FLAG_CLOSE | FLAG_HEARTBEAT | FLAG_TICKET;
+const KEYUPDATELABEL: &[u8] = b"kyberauth traffic key update";
+/// When the sending side derives a new traffic key from the current one. The peer follows the update
+/// on the record that triggered it, so both sides switch keys in lockstep. `None` disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Number of records encrypted with a key. A record carries up to 10000 bytes of a message.
+    pub records: Option<u64>,
+    /// Number of plaintext bytes encrypted with a key.
+    pub bytes: Option<u64>,
+    /// Time elapsed since the key was installed, checked when a record is sent.
+    pub interval: Option<Duration>,
+}
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            records: Some(1 << 24),
+            bytes: None,
+            interval: None,
+        }
+    }
+}
+/// How the data of each record is padded before encryption to hide its exact size. The padding is authenticated
+/// and removed by the peer whatever its own policy. Data and padding of a record never exceed 10000 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingPolicy {
+    /// No padding, the size of a record reveals the size of its data.
+    #[default]
+    None,
+    /// Pad the data to a multiple of the given number of bytes.
+    Block(usize),
+    /// Pad the data to the next power of two.
+    PowerOfTwo,
+    /// Pad every record to the maximum size.
+    Constant,
+}
+impl PaddingPolicy {
+    /// Size of the data once padded
+    fn paddedsize(&self, size: usize) -> usize {
+        let padded = match *self {
+            PaddingPolicy::None => size,
+            PaddingPolicy::Block(block) if block > 0 => size.max(1).div_ceil(block) * block,
+            PaddingPolicy::Block(_) => size,
+            PaddingPolicy::PowerOfTwo => size.max(1).next_power_of_two(),
+            PaddingPolicy::Constant => CHUNKSIZE,
+        };
+        padded.min(CHUNKSIZE).max(size)
+    }
+}
+/// Heartbeats and deadlines of a connection, everything is disabled by default. A deadline that expires returns an error
+/// of kind `TimedOut` and closes the connection, as a record might have been cut in the middle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeepalivePolicy {
+    /// Send a heartbeat when nothing was sent for this long while `receivedata` waits for the peer.
+    pub heartbeat: Option<Duration>,
+    /// Maximum time without any record from the peer, heartbeats included, while waiting for a message.
+    pub idle: Option<Duration>,
+    /// Maximum time to send a message, or to receive the rest of a message once its first record arrived.
+    pub operation: Option<Duration>,
+}
+/// Error returned when a record does not carry the expected sequence number, see [`KyberauthError::Sequence`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceError {
+    /// The record was already received or arrived after a newer one.
+    Replayed { expected: u64, received: u64 },
+    /// Some records were dropped or the record arrived before an older one.
+    Gap { expected: u64, received: u64 },
+}
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SequenceError::Replayed { expected, received } => write!(
+                f,
+                "Record {} replayed or reordered, expected {}",
+                received, expected
+            ),
+            SequenceError::Gap { expected, received } => write!(
+                f,
+                "Record {} received before {}, records are missing or reordered",
+                received, expected
+            ),
+        }
+    }
+}
+impl std::error::Error for SequenceError {}
+/// AEAD used to encrypt records, agreed on during the handshake. All suites use 256-bit keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    /// AES-256-GCM, fastest on hosts with AES-NI.
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, fastest on hosts without AES instructions.
+    ChaCha20Poly1305,
+    /// AES-256-GCM-SIV, resistant to nonce misuse.
+    Aes256GcmSiv,
+}
+impl CipherSuite {
+    /// Every suite, in the default order of preference.
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::Aes256Gcm,
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::Aes256GcmSiv,
+    ];
+    /// Identifier of the suite on the wire
+    pub fn getid(&self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+            CipherSuite::Aes256GcmSiv => 3,
+        }
+    }
+    pub fn fromid(id: u8) -> Option<CipherSuite> {
+        CipherSuite::ALL.into_iter().find(|suite| suite.getid() == id)
+    }
+}
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherSuite::Aes256Gcm => write!(f, "AES-256-GCM"),
+            CipherSuite::ChaCha20Poly1305 => write!(f, "ChaCha20-Poly1305"),
+            CipherSuite::Aes256GcmSiv => write!(f, "AES-256-GCM-SIV"),
+        }
+    }
+}
+/// What the peer sent, heartbeats and tickets are not returned to the caller.
+pub(crate) enum Received {
+    Message,
+    Heartbeat,
+    Close,
+    Ticket,
+}
+/// Instance of the AEAD of a suite, keyed with a traffic key. It is kept until the key is updated. Its key schedule is
+/// wiped when it is dropped, with the `zeroize` features of the AES, GHASH, POLYVAL and Poly1305 crates.
+#[derive(Clone)]
+pub(crate) enum Cipher {
+    Aes256Gcm(Aes256Gcm),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes256GcmSiv(Aes256GcmSiv),
+}
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        //The expanded key is not printed
+        match self {
+            Cipher::Aes256Gcm(_) => write!(f, "Cipher({})", CipherSuite::Aes256Gcm),
+            Cipher::ChaCha20Poly1305(_) => write!(f, "Cipher({})", CipherSuite::ChaCha20Poly1305),
+            Cipher::Aes256GcmSiv(_) => write!(f, "Cipher({})", CipherSuite::Aes256GcmSiv),
+        }
+    }
+}
+impl Cipher {
+    fn new(suite: CipherSuite, key: &[u8; KYBER_SSBYTES]) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => Cipher::Aes256Gcm(Aes256Gcm::new(key.into())),
+            CipherSuite::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into())),
+            CipherSuite::Aes256GcmSiv => Cipher::Aes256GcmSiv(Aes256GcmSiv::new(key.into())),
+        }
+    }
+    /// Encrypt the buffer in place, its last bytes are overwritten by the tag.
+    fn encryptinplace(&self, nonce: &[u8; NONCESIZE], aad: &[u8], buffer: &mut [u8]) -> Result<(), aes_gcm::Error> {
+        let (data, tag) = buffer.split_at_mut(buffer.len() - TAGSIZE);
+        let computed = match self {
+            Cipher::Aes256Gcm(cipher) => cipher.encrypt_in_place_detached(nonce.into(), aad, data)?,
+            Cipher::ChaCha20Poly1305(cipher) => cipher.encrypt_in_place_detached(nonce.into(), aad, data)?,
+            Cipher::Aes256GcmSiv(cipher) => cipher.encrypt_in_place_detached(nonce.into(), aad, data)?,
+        };
+        tag.copy_from_slice(&computed);
+        Ok(())
+    }
+    /// Decrypt the buffer in place, its last bytes are the tag. The buffer is left encrypted if the tag does not match.
+    fn decryptinplace(&self, nonce: &[u8; NONCESIZE], aad: &[u8], buffer: &mut [u8]) -> Result<(), aes_gcm::Error> {
+        let (data, tag) = buffer.split_at_mut(buffer.len() - TAGSIZE);
+        let tag = aes_gcm::Tag::from_slice(tag);
+        match self {
+            Cipher::Aes256Gcm(cipher) => cipher.decrypt_in_place_detached(nonce.into(), aad, data, tag),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.decrypt_in_place_detached(nonce.into(), aad, data, tag),
+            Cipher::Aes256GcmSiv(cipher) => cipher.decrypt_in_place_detached(nonce.into(), aad, data, tag),
+        }
+    }
+}
 #[derive(Debug, Zeroize)]
 pub struct Connection {
     #[zeroize(skip)]
-    socket: TcpStream,
+    pub(crate) socket: TcpStream,
     #[zeroize(skip)]
     pub peer_addr: SocketAddr,
     pub pubkey: String,
-    aeskey: [u8; KYBER_SSBYTES],
+    #[zeroize(skip)]
+    pub(crate) role: Role,
+    pub(crate) exporter: Exporter,
+    pub(crate) ticket: Option<Ticket>,
+    pub(crate) early: Option<EarlyData>,
+    #[zeroize(skip)]
+    pub(crate) negotiated: Negotiated,
+    pub(crate) tx: SendState,
+    pub(crate) rx: RecvState,
 }
 impl Connection {
-    /// Flush and shutdown the socket
-    pub async fn clean(&mut self) -> io::Result<()> {
-        let socket = &mut self.socket;
-        socket.flush().await?;
-        socket.shutdown().await?;
-        Ok(())
+    /// Send an authenticated close notify then flush and shutdown the sending side of the socket.
+    /// Data can still be received until the peer closes its side, `receivedata` then returns `None`.
+    pub async fn clean(&mut self) -> Result<(), KyberauthError> {
+        Ok(self.tx.close(&mut self.socket).await?)
+    }
+    /// Send a heartbeat record, the peer skips it but knows the connection is alive.
+    pub async fn sendheartbeat(&mut self) -> Result<(), KyberauthError> {
+        let result = self.tx.heartbeat(&mut self.socket).await;
+        Ok(self.expire(result).await?)
     }
-    /// Create a new connection with pubkey and to transmit aeskey. AES key is hidden and cannot be retrieved for security reasons.
+    /// Create a new connection with pubkey and the AKE shared secret. Keys of each direction are derived from the secret
+    /// and the handshake transcript hash. AES keys are hidden and cannot be retrieved for security reasons.
     pub(crate) fn new(
         socket: TcpStream,
         peer_addr: SocketAddr,
         pubkey: String,
-        aeskey: [u8; KYBER_SSBYTES],
+        mut sharedsecret: [u8; KYBER_SSBYTES],
+        transcript: [u8; HASHSIZE],
+        role: Role,
+        suite: CipherSuite,
     ) -> Self {
+        let secrets = TrafficSecrets::new(&sharedsecret, &transcript);
+        sharedsecret.zeroize();
+        let exporter = Exporter(secrets.exporter);
+        let (tx, rx) = match role {
+            Role::Client => (
+                SendState::new(suite, secrets.clientkey, secrets.clientiv),
+                RecvState::new(suite, secrets.serverkey, secrets.serveriv),
+            ),
+            Role::Server => (
+                SendState::new(suite, secrets.serverkey, secrets.serveriv),
+                RecvState::new(suite, secrets.clientkey, secrets.clientiv),
+            ),
+        };
         Connection {
             socket,
             peer_addr,
             pubkey,
-            aeskey,
+            role,
+            exporter,
+            ticket: None,
+            early: None,
+            negotiated: Negotiated::default(),
+            tx,
+            rx,
         }
     }
+    /// Get the cipher suite agreed on during the handshake
+    pub fn getciphersuite(&self) -> CipherSuite {
+        self.tx.suite
+    }
+    /// Get the protocol version chosen by the server during the handshake
+    pub fn getversion(&self) -> u16 {
+        self.negotiated.version
+    }
+    /// Get the extensions sent by the peer in its hello, see [`crate::hello`].
+    pub fn getpeerextensions(&self) -> &[Extension] {
+        &self.negotiated.extensions
+    }
+    /// Get the parameter set of the keys used by the handshake, see [`crate::key`].
+    pub fn getkem(&self) -> Kem {
+        self.negotiated.kem
+    }
+    /// Whether Kyber was combined with X25519 during the handshake, see [`crate::hybrid`].
+    pub fn ishybrid(&self) -> bool {
+        self.negotiated.hybrid
+    }
+    /// Set the maximum size of a message that can be sent or received. Bigger messages return an error.
+    pub fn setmaxsize(&mut self, maxsize: usize) {
+        self.tx.maxsize = maxsize;
+        self.rx.maxsize = maxsize;
+    }
+    pub fn getmaxsize(&self) -> usize {
+        self.tx.maxsize
+    }
+    /// Set when the key used to send data is updated. The peer does not need the same policy.
+    pub fn setrekeypolicy(&mut self, policy: RekeyPolicy) {
+        self.tx.rekey = policy;
+    }
+    pub fn getrekeypolicy(&self) -> RekeyPolicy {
+        self.tx.rekey
+    }
+    /// Set how the records sent are padded. The peer removes the padding whatever its own policy.
+    pub fn setpaddingpolicy(&mut self, policy: PaddingPolicy) {
+        self.tx.padding = policy;
+    }
+    pub fn getpaddingpolicy(&self) -> PaddingPolicy {
+        self.tx.padding
+    }
+    /// Set the heartbeats and deadlines of the connection. Heartbeats are sent while `receivedata` waits for the peer.
+    pub fn setkeepalivepolicy(&mut self, policy: KeepalivePolicy) {
+        self.tx.keepalive = policy;
+        self.rx.keepalive = policy;
+    }
+    pub fn getkeepalivepolicy(&self) -> KeepalivePolicy {
+        self.tx.keepalive
+    }
+    /// Get the number of times the key used to send data was updated
+    pub fn getsendepoch(&self) -> u64 {
+        self.tx.epoch
+    }
+    /// Get the number of times the key used to receive data was updated
+    pub fn getreceiveepoch(&self) -> u64 {
+        self.rx.epoch
+    }
+    /// Get the sequence number of the next record to be sent
+    pub fn getsendsequence(&self) -> u64 {
+        self.tx.seq
+    }
+    /// Get the sequence number expected for the next record to be received
+    pub fn getreceivesequence(&self) -> u64 {
+        self.rx.seq
+    }
     /// Get peer public key
     pub fn getpeerkey(&self,hex: bool) -> Result<Vec<u8>,hex::FromHexError> {
         if hex {
@@ -51,65 +366,754 @@ impl Connection {
             Ok(hex::decode(self.pubkey.clone())?.to_vec())
         }
     }
+    /// Derive `len` bytes bound to this connection, the same on both sides for the same label and context.
+    /// The output comes from the handshake secret through HKDF and reveals nothing about the traffic keys.
+    /// Returns an error if more than 8160 bytes are asked.
+    pub fn export_keying_material<L, C>(&self, label: L, context: C, len: usize) -> Result<Vec<u8>, KyberauthError>
+    where
+        L: AsRef<[u8]>,
+        C: AsRef<[u8]>,
+    {
+        self.exporter.export(label.as_ref(), context.as_ref(), len)
+    }
+    /// Issue a resumption ticket to the client, encrypted with the current key of `keys`. The client reads it with its next
+    /// `receivedata` and gets it with [`Connection::taketicket`]. Only the server issues tickets, not in the hybrid mode.
+    pub async fn sendticket(&mut self, keys: &TicketKeys) -> Result<(), KyberauthError> {
+        if self.role != Role::Server {
+            return Err(KyberauthError::InvalidConfig("Only the server issues tickets"));
+        }
+        if self.negotiated.hybrid {
+            return Err(KyberauthError::InvalidConfig("No tickets in the hybrid mode"));
+        }
+        let clientkey = self.getpeerkey(false).unwrap_or_default();
+        let record = ticket::issue(keys, &self.exporter, self.tx.suite, self.negotiated.kem, &clientkey)?;
+        let result = self.tx.sendrecord(&mut self.socket, 0, FLAG_FINAL | FLAG_TICKET, &record, &[]).await;
+        Ok(self.expire(result).await?)
+    }
+    /// Take the last ticket received from the server, see [`crate::client::resume`].
+    pub fn taketicket(&mut self) -> Option<Ticket> {
+        self.ticket.take()
+    }
+    /// Take the early data sent by the client in its first flight, see [`crate::early`]. Only the server has early data,
+    /// and only if it accepted it. Early data might be a replay, unlike the data received with `receivedata`.
+    pub fn takeearlydata(&mut self) -> Option<EarlyData> {
+        self.early.take()
+    }
+    /// Read the first record of the peer, which must be a heartbeat. It proves that the peer holds the secret key of
+    /// the handshake.
+    pub(crate) async fn readheartbeat(&mut self) -> Result<(), KyberauthError> {
+        let mut buffer: Vec<u8> = Vec::new();
+        match self.rx.next(&mut self.socket, &[], &mut buffer).await? {
+            Received::Heartbeat => Ok(()),
+            _ => Err(KyberauthError::Handshake("Expected a heartbeat of the client")),
+        }
+    }
     pub fn getsocket(self) -> TcpStream {
         self.socket
     }
     pub fn getpeer(&self) -> SocketAddr {
         self.peer_addr
     }
+    /// Split the connection in a reader and a writer that can be used from different tasks.
+    /// Each half keeps the keys and sequence numbers of its direction.
+    pub fn into_split(self) -> (EncryptedReader, EncryptedWriter) {
+        let (reader, writer) = self.socket.into_split();
+        let mut reader = EncryptedReader::new(
+            reader,
+            self.peer_addr,
+            self.pubkey.clone(),
+            self.role,
+            self.exporter.clone(),
+            self.ticket,
+            self.rx,
+        );
+        reader.kem = self.negotiated.kem;
+        (
+            reader,
+            EncryptedWriter::new(writer, self.peer_addr, self.pubkey, self.exporter, self.negotiated, self.tx),
+        )
+    }
     /// Encrypt data via AES key into the connection, might return an error.
-    pub async fn senddata<T>(&mut self, text: T) -> io::Result<()> where T: AsRef<[u8]>{
-        let cipher = self.encryptdata(text);
-        if cipher.is_err() {
-            return Err(io::Error::from(ErrorKind::InvalidData));
-        }
-        let cipher = cipher.unwrap();
+    /// Data bigger than a record is split in several authenticated records sent one after the other.
+    pub async fn senddata<T>(&mut self, text: T) -> Result<(), KyberauthError> where T: AsRef<[u8]>{
+        self.senddata_with_aad(text, []).await
+    }
+    /// Same as `senddata`, the associated data is authenticated with the message but not sent.
+    /// The peer must give the same associated data to `receivedata_with_aad`.
+    pub async fn senddata_with_aad<T, A>(&mut self, text: T, aad: A) -> Result<(), KyberauthError>
+    where
+        T: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
         self.socket.writable().await?;
-        self.socket.write_all(&cipher).await?;
-        self.socket.flush().await?;
+        let result = self.tx.send(&mut self.socket, text.as_ref(), aad.as_ref()).await;
+        Ok(self.expire(result).await?)
+    }
+    /// Receive one encrypted message and decrypt it. Every call returns exactly what one `senddata` sent. Might return an error.
+    /// Returns `None` once the peer closed the connection with `clean`, an end of stream without it is an `UnexpectedEof` error.
+    /// Heartbeats of the peer are skipped, see [`Connection::setkeepalivepolicy`].
+    pub async fn receivedata(&mut self) -> Result<Option<Vec<u8>>, KyberauthError> {
+        self.receivedata_with_aad([]).await
+    }
+    /// Same as `receivedata`, fails if the associated data does not match the one given by the peer to `senddata_with_aad`.
+    pub async fn receivedata_with_aad<A>(&mut self, aad: A) -> Result<Option<Vec<u8>>, KyberauthError> where A: AsRef<[u8]> {
+        let mut message: Vec<u8> = Vec::new();
+        Ok(self.receiveinto(aad.as_ref(), &mut message).await?.map(|_| message))
+    }
+    /// Same as `receivedata` but the message replaces the content of the buffer, whose memory is reused between calls.
+    /// Records are read and decrypted in place. Returns the size of the message, or `None` once the peer closed the connection.
+    pub async fn receive_into(&mut self, buffer: &mut Vec<u8>) -> Result<Option<usize>, KyberauthError> {
+        Ok(self.receiveinto(&[], buffer).await?)
+    }
+    async fn receiveinto(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<Option<usize>> {
+        loop {
+            let result = self.waitpeer().await;
+            self.expire(result).await?;
+            let result = self.rx.next(&mut self.socket, aad, buffer).await;
+            match self.expire(result).await? {
+                Received::Message => return Ok(Some(buffer.len())),
+                Received::Close => return Ok(None),
+                Received::Heartbeat => {}
+                Received::Ticket => {
+                    self.ticket = Some(ticket::receive(self.role, buffer, &self.exporter, self.rx.suite, self.negotiated.kem, &self.pubkey)?);
+                    buffer.clear();
+                }
+            }
+        }
+    }
+    /// Wait until the peer sent something, sending heartbeats meanwhile if the policy asks for it.
+    async fn waitpeer(&mut self) -> io::Result<()> {
+        let idle = self.rx.keepalive.idle.map(|idle| Instant::now() + idle);
+        //The readiness of the socket might be left over from a previous read, so peek to wait for new data
+        let mut byte = [0u8; 1];
+        loop {
+            let heartbeat = match self.tx.keepalive.heartbeat {
+                Some(interval) if !self.tx.closed => Some(self.tx.lastsend + interval),
+                _ => None,
+            };
+            let readable = tokio::select! {
+                readable = self.socket.peek(&mut byte) => Some(readable.map(|_| ())),
+                _ = sleepuntil(heartbeat) => None,
+                _ = sleepuntil(idle) => Some(Err(KyberauthError::TimedOut("Peer idle for too long").into())),
+            };
+            match readable {
+                Some(readable) => return readable,
+                None => self.tx.heartbeat(&mut self.socket).await?,
+            }
+        }
+    }
+    /// Close the connection if a deadline expired, the peer sees the end of stream without close notify.
+    async fn expire<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if matches!(&result, Err(e) if e.kind() == ErrorKind::TimedOut) {
+            self.tx.timedout = true;
+            self.rx.timedout = true;
+            let _ = self.socket.shutdown().await;
+        }
+        result
+    }
+    /// Encrypt data without sending to the socket. The output contains every record of the message and must be written to the socket
+    /// before any other data is sent, as records are numbered. Returns an error if the data exceeds the maximum size.
+    pub fn encryptdata<T>(&mut self, input: T) -> Result<Vec<u8>, KyberauthError> where T: AsRef<[u8]> {
+        self.encryptdata_with_aad(input, [])
+    }
+    /// Same as `encryptdata`, the associated data is authenticated with the message but not included in the output.
+    pub fn encryptdata_with_aad<T, A>(&mut self, input: T, aad: A) -> Result<Vec<u8>, KyberauthError>
+    where
+        T: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
+        self.tx.encrypt(input.as_ref(), aad.as_ref())
+    }
+    /// Same as `encryptdata` but the message in the buffer is replaced by its records, without other allocation than
+    /// growing the buffer if its capacity is too small, see [`Connection::encryptedsize`].
+    pub fn encrypt_in_place(&mut self, buffer: &mut Vec<u8>) -> Result<(), KyberauthError> {
+        self.tx.encryptinplace(buffer, &[])
+    }
+    /// Get the size of the output of `encryptdata` for a message of `size` bytes, with the current padding policy.
+    pub fn encryptedsize(&self, size: usize) -> usize {
+        self.tx.encryptedsize(size)
+    }
+    /// Decrypt data produced by `encryptdata` on the peer without reading from the socket. Returns an error if records are missing,
+    /// replayed, reordered, tampered with or if the message exceeds the maximum size. The receive sequence only advances on success.
+    pub fn decryptdata<T>(&mut self, input: T) -> Result<Vec<u8>, KyberauthError> where T: AsRef<[u8]> {
+        self.decryptdata_with_aad(input, [])
+    }
+    /// Same as `decryptdata`, fails if the associated data does not match the one given to `encryptdata_with_aad`.
+    pub fn decryptdata_with_aad<T, A>(&mut self, input: T, aad: A) -> Result<Vec<u8>, KyberauthError>
+    where
+        T: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
+        Ok(self.rx.decrypt(input.as_ref(), aad.as_ref())?)
+    }
+    /// Same as `decryptdata` but the records in the buffer are replaced by the message, without allocation.
+    /// The buffer is emptied on error.
+    pub fn decrypt_in_place(&mut self, buffer: &mut Vec<u8>) -> Result<(), KyberauthError> {
+        Ok(self.rx.decryptinplace(buffer, &[])?)
+    }
+}
+/// Keys and counters used to encrypt the records of one direction.
+#[derive(Debug, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct SendState {
+    #[zeroize(skip)]
+    pub(crate) suite: CipherSuite,
+    key: [u8; KYBER_SSBYTES],
+    iv: [u8; NONCESIZE],
+    //Wiped by its own drop
+    #[zeroize(skip)]
+    cipher: Cipher,
+    pub(crate) seq: u64,
+    pub(crate) maxsize: usize,
+    pub(crate) closed: bool,
+    pub(crate) timedout: bool,
+    #[zeroize(skip)]
+    pub(crate) rekey: RekeyPolicy,
+    #[zeroize(skip)]
+    pub(crate) padding: PaddingPolicy,
+    #[zeroize(skip)]
+    pub(crate) keepalive: KeepalivePolicy,
+    #[zeroize(skip)]
+    pub(crate) lastsend: Instant,
+    //Usage of the current key
+    records: u64,
+    bytes: u64,
+    #[zeroize(skip)]
+    keytime: Instant,
+    pub(crate) epoch: u64,
+}
+impl SendState {
+    pub(crate) fn new(suite: CipherSuite, key: [u8; KYBER_SSBYTES], iv: [u8; NONCESIZE]) -> Self {
+        SendState {
+            suite,
+            cipher: Cipher::new(suite, &key),
+            key,
+            iv,
+            seq: 0,
+            maxsize: DEFAULTMAXSIZE,
+            closed: false,
+            timedout: false,
+            rekey: RekeyPolicy::default(),
+            padding: PaddingPolicy::default(),
+            keepalive: KeepalivePolicy::default(),
+            lastsend: Instant::now(),
+            records: 0,
+            bytes: 0,
+            keytime: Instant::now(),
+            epoch: 0,
+        }
+    }
+    /// Encrypt a message and write its records to the socket.
+    pub(crate) async fn send<W>(&mut self, socket: &mut W, text: &[u8], aad: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.checkopen()?;
+        if text.len() > self.maxsize {
+            return Err(KyberauthError::MessageTooLarge.into());
+        }
+        let result = deadline(self.keepalive.operation, self.write(socket, text, aad)).await;
+        self.lastsend = Instant::now();
+        if matches!(&result, Err(e) if e.kind() == ErrorKind::TimedOut) {
+            self.timedout = true;
+        }
+        result
+    }
+    /// Send a heartbeat record, it counts as a record for the rekey policy.
+    pub(crate) async fn heartbeat<W>(&mut self, socket: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.sendrecord(socket, 0, FLAG_FINAL | FLAG_HEARTBEAT, &[], &[]).await
+    }
+    /// Encrypt a single record and write it to the socket, within the operation deadline.
+    pub(crate) async fn sendrecord<W>(&mut self, socket: &mut W, index: u32, flags: u8, data: &[u8], aad: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.checkopen()?;
+        let record = self.seal(index, flags, data, aad)?;
+        let result = deadline(self.keepalive.operation, async {
+            socket.write_all(&record).await?;
+            socket.flush().await
+        })
+        .await;
+        self.lastsend = Instant::now();
+        if matches!(&result, Err(e) if e.kind() == ErrorKind::TimedOut) {
+            self.timedout = true;
+        }
+        result
+    }
+    /// Returns an error if nothing can be sent anymore.
+    fn checkopen(&self) -> io::Result<()> {
+        if self.timedout {
+            return Err(KyberauthError::TimedOut("Connection closed after a timeout").into());
+        }
+        if self.closed {
+            return Err(KyberauthError::Closed.into());
+        }
+        Ok(())
+    }
+    /// Encrypt the records of a message one by one and write them to the socket, reusing the same buffer.
+    async fn write<W>(&mut self, socket: &mut W, text: &[u8], aad: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let chunks = chunks(text);
+        let last = chunks.len() - 1;
+        let mut record: Vec<u8> = Vec::with_capacity(HEADERSIZE + RECORDSIZE);
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let flags = if index == last { FLAG_FINAL } else { 0 };
+            record.clear();
+            self.sealinto(index as u32, flags, chunk, aad, &mut record)?;
+            socket.write_all(&record).await?;
+        }
+        record.zeroize();
+        socket.flush().await?;
+        Ok(())
+    }
+    /// Send the close notify record if not done yet, then shutdown the socket.
+    pub(crate) async fn close<W>(&mut self, socket: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        //After a timeout a record might have been cut, so the close notify could not be read
+        if !self.closed && !self.timedout {
+            let record = self.sealclose()?;
+            socket.write_all(&record).await?;
+        }
+        socket.flush().await?;
+        socket.shutdown().await?;
+        Ok(())
+    }
+    /// Encrypt the close notify record, nothing can be sent after it.
+    pub(crate) fn sealclose(&mut self) -> Result<Vec<u8>, KyberauthError> {
+        let record = self.seal(0, FLAG_FINAL | FLAG_CLOSE, &[], &[])?;
+        self.closed = true;
+        Ok(record)
+    }
+    /// Encrypt a message in records, returns an error if the data exceeds the maximum size.
+    pub(crate) fn encrypt(&mut self, input: &[u8], aad: &[u8]) -> Result<Vec<u8>, KyberauthError> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(self.encryptedsize(input.len()));
+        buffer.extend_from_slice(input);
+        self.encryptinplace(&mut buffer, aad)?;
+        Ok(buffer)
+    }
+    /// Replace the message in the buffer by its records. The buffer only grows if its capacity is too small.
+    pub(crate) fn encryptinplace(&mut self, buffer: &mut Vec<u8>, aad: &[u8]) -> Result<(), KyberauthError> {
+        if self.timedout {
+            return Err(KyberauthError::TimedOut("Connection closed after a timeout"));
+        }
+        if self.closed {
+            return Err(KyberauthError::Closed);
+        }
+        if buffer.len() > self.maxsize {
+            return Err(KyberauthError::MessageTooLarge);
+        }
+        let size = buffer.len();
+        let count = size.div_ceil(CHUNKSIZE).max(1);
+        //Every record but the last one carries a full chunk
+        let full = self.recordsize(CHUNKSIZE);
+        let offset = HEADERSIZE + SEQSIZE + self.prefix();
+        buffer.resize(self.encryptedsize(size), 0);
+        //Move the chunks to their place from the last one, so none is overwritten before being moved
+        for index in (0..count).rev() {
+            let start = index * CHUNKSIZE;
+            let end = size.min(start + CHUNKSIZE);
+            buffer.copy_within(start..end, index * full + offset);
+        }
+        for index in 0..count {
+            let start = index * full;
+            let length = size.min((index + 1) * CHUNKSIZE) - index * CHUNKSIZE;
+            let end = start + self.recordsize(length);
+            let flags = if index == count - 1 { FLAG_FINAL } else { 0 };
+            if let Err(e) = self.sealrecord(index as u32, flags, &mut buffer[start..end], length, aad) {
+                buffer.zeroize();
+                return Err(e);
+            }
+        }
         Ok(())
     }
-    /// Receive encrypted data and decrypt it. The vec is reallocated. Might return an error.
-    pub async fn receivedata(&mut self) -> io::Result<Vec<u8>> {
-        let mut vec = vec![0;MAXSIZE];
-        self.socket.readable().await?;
-        let size = self.socket.read(&mut vec).await?;
-        vec.resize(size, 0);
-        let cipher = self.decryptdata(vec);
-        if cipher.is_err() {
-            return Err(io::Error::from(ErrorKind::InvalidData));
-        }
-        Ok(cipher.unwrap())
-    }
-    /// Encrypt data without sending to the socket. Might return an error.
-    pub fn encryptdata<T>(&self, input: T) -> Result<Vec<u8>, aes_gcm::Error> where T: AsRef<[u8]> {
-        // Alternatively, the key can be transformed directly from a byte slice
-        // (panicks on length mismatch):
-        let input = input.as_ref();
-        if input.len() > MAXSIZE {
-            return Ok(Vec::new());
-        }
-        let key = Key::<Aes256Gcm>::from_slice(&self.aeskey);
-
-        let cipher = Aes256Gcm::new(key);
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
-        let mut ciphertext = cipher.encrypt(&nonce, input.as_ref())?;
-        let mut finale: Vec<u8> = Vec::new();
-        finale.extend_from_slice(nonce.as_ref());
-        finale.append(&mut ciphertext);
-        Ok(finale)
-    }
-    /// Decrypt data without sending to the socket. Might return an error.
-    pub fn decryptdata<T>(&self, input: T) -> Result<Vec<u8>, aes_gcm::Error> where T: AsRef<[u8]> {
-        let input = input.as_ref();
-        if input.len() > MAXSIZE || input.len() < NONCESIZE {
-            return Ok(Vec::new());
-        }
-        let key = Key::<Aes256Gcm>::from_slice(&self.aeskey);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = &input[..NONCESIZE];
-        let input: &[u8] = &input[NONCESIZE..];
-        let plaintext = cipher.decrypt(nonce.as_ref().into(), input.as_ref())?;
-        Ok(plaintext)
+    /// Size of the records of a message of `size` bytes.
+    pub(crate) fn encryptedsize(&self, size: usize) -> usize {
+        let count = size.div_ceil(CHUNKSIZE).max(1);
+        (count - 1) * self.recordsize(CHUNKSIZE) + self.recordsize(size - (count - 1) * CHUNKSIZE)
+    }
+    /// Size of a record carrying `size` bytes of data, with its length header.
+    fn recordsize(&self, size: usize) -> usize {
+        HEADERSIZE + SEQSIZE + self.prefix() + self.padding.paddedsize(size) + TAGSIZE
+    }
+    /// Size of the plaintext of a record before its data.
+    fn prefix(&self) -> usize {
+        match self.padding {
+            PaddingPolicy::None => 1,
+            _ => 1 + PADHEADER,
+        }
+    }
+    /// Encrypt one record with its length header and sequence number.
+    pub(crate) fn seal(&mut self, index: u32, flags: u8, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, KyberauthError> {
+        let mut record: Vec<u8> = Vec::with_capacity(self.recordsize(data.len()));
+        self.sealinto(index, flags, data, aad, &mut record)?;
+        Ok(record)
+    }
+    /// Encrypt one record at the end of the buffer.
+    pub(crate) fn sealinto(&mut self, index: u32, flags: u8, data: &[u8], aad: &[u8], buffer: &mut Vec<u8>) -> Result<(), KyberauthError> {
+        let start = buffer.len();
+        let offset = start + HEADERSIZE + SEQSIZE + self.prefix();
+        buffer.resize(start + self.recordsize(data.len()), 0);
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+        let result = self.sealrecord(index, flags, &mut buffer[start..], data.len(), aad);
+        if result.is_err() {
+            buffer.truncate(start);
+        }
+        result
+    }
+    /// Encrypt a record in place, its `size` bytes of data are already at their place after the header and flags.
+    /// The sequence number is used as nonce and the index of the record in its message is authenticated with the
+    /// associated data to detect reordering. The key is updated after the record if the rekey policy says so.
+    fn sealrecord(&mut self, index: u32, flags: u8, record: &mut [u8], size: usize, aad: &[u8]) -> Result<(), KyberauthError> {
+        let seq = self.seq;
+        self.seq = seq.checked_add(1).ok_or(KyberauthError::SequenceExhausted)?;
+        self.records += 1;
+        self.bytes += size as u64;
+        let update = self.rekey.records.is_some_and(|limit| self.records >= limit)
+            || self.rekey.bytes.is_some_and(|limit| self.bytes >= limit)
+            || self.rekey.interval.is_some_and(|limit| self.keytime.elapsed() >= limit);
+        let mut flags = if update { flags | FLAG_KEYUPDATE } else { flags };
+        let prefix = self.prefix();
+        let length = (record.len() - HEADERSIZE) as u32;
+        record[..HEADERSIZE].copy_from_slice(&length.to_be_bytes());
+        record[HEADERSIZE..HEADERSIZE + SEQSIZE].copy_from_slice(&seq.to_be_bytes());
+        let plaintext = &mut record[HEADERSIZE + SEQSIZE..];
+        if prefix > 1 {
+            flags |= FLAG_PADDED;
+            plaintext[1..prefix].copy_from_slice(&(size as u16).to_be_bytes());
+        }
+        plaintext[0] = flags;
+        let end = plaintext.len() - TAGSIZE;
+        plaintext[prefix + size..end].fill(0);
+        let nonce = nonce(&self.iv, seq);
+        withrecordaad(index, aad, |aad| self.cipher.encryptinplace(&nonce, aad, plaintext))
+            .map_err(|_| KyberauthError::Encryption)?;
+        if update {
+            ratchet(self.suite, &mut self.key, &mut self.cipher);
+            self.epoch += 1;
+            self.records = 0;
+            self.bytes = 0;
+            self.keytime = Instant::now();
+        }
+        Ok(())
+    }
+}
+/// Keys and counters used to decrypt the records of one direction.
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct RecvState {
+    #[zeroize(skip)]
+    pub(crate) suite: CipherSuite,
+    key: [u8; KYBER_SSBYTES],
+    iv: [u8; NONCESIZE],
+    //Wiped by its own drop
+    #[zeroize(skip)]
+    cipher: Cipher,
+    pub(crate) seq: u64,
+    pub(crate) maxsize: usize,
+    pub(crate) epoch: u64,
+    pub(crate) closed: bool,
+    pub(crate) timedout: bool,
+    #[zeroize(skip)]
+    pub(crate) keepalive: KeepalivePolicy,
+}
+impl RecvState {
+    pub(crate) fn new(suite: CipherSuite, key: [u8; KYBER_SSBYTES], iv: [u8; NONCESIZE]) -> Self {
+        RecvState {
+            suite,
+            cipher: Cipher::new(suite, &key),
+            key,
+            iv,
+            seq: 0,
+            maxsize: DEFAULTMAXSIZE,
+            epoch: 0,
+            closed: false,
+            timedout: false,
+            keepalive: KeepalivePolicy::default(),
+        }
+    }
+    /// Read the next message, heartbeat, ticket or close notify from the socket within the deadlines of the keepalive policy.
+    /// A message or a ticket replaces the content of the buffer, the buffer is emptied otherwise.
+    pub(crate) async fn next<R>(&mut self, socket: &mut R, aad: &[u8], buffer: &mut Vec<u8>) -> io::Result<Received>
+    where
+        R: AsyncRead + Unpin,
+    {
+        buffer.clear();
+        if self.timedout {
+            return Err(KyberauthError::TimedOut("Connection closed after a timeout").into());
+        }
+        if self.closed {
+            return Ok(Received::Close);
+        }
+        let keepalive = self.keepalive;
+        let result = async {
+            let mut header = [0u8; HEADERSIZE];
+            deadline(keepalive.idle, socket.read_exact(&mut header))
+                .await
+                .map_err(|e| truncated(e, "Connection closed without close notify"))?;
+            deadline(keepalive.operation, self.read(socket, header, aad, buffer)).await
+        }
+        .await;
+        match &result {
+            Ok(Received::Message | Received::Ticket) => {}
+            Ok(_) => buffer.clear(),
+            Err(e) => {
+                if e.kind() == ErrorKind::TimedOut {
+                    self.timedout = true;
+                }
+                buffer.zeroize();
+            }
+        }
+        result
+    }
+    /// Read the records of one message in the buffer and decrypt them in place, the length header of the first one is already read.
+    async fn read<R>(&mut self, socket: &mut R, mut header: [u8; HEADERSIZE], aad: &[u8], message: &mut Vec<u8>) -> io::Result<Received>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut index: u32 = 0;
+        loop {
+            let size = u32::from_be_bytes(header) as usize;
+            if size > RECORDSIZE {
+                return Err(KyberauthError::InvalidRecord("Record too large").into());
+            }
+            let start = message.len();
+            message.resize(start + size, 0);
+            socket
+                .read_exact(&mut message[start..])
+                .await
+                .map_err(|e| truncated(e, "Connection closed in the middle of a message"))?;
+            let (flags, data) = self.open(index, &mut message[start..], aad)?;
+            if flags & FLAG_CONTROL != 0 {
+                if index != 0 {
+                    return Err(KyberauthError::InvalidRecord("Control record in the middle of a message").into());
+                }
+                if flags & FLAG_CLOSE != 0 {
+                    self.closed = true;
+                    return Ok(Received::Close);
+                }
+                if flags & FLAG_TICKET != 0 {
+                    message.copy_within(start + data.start..start + data.end, start);
+                    message.truncate(start + data.len());
+                    return Ok(Received::Ticket);
+                }
+                return Ok(Received::Heartbeat);
+            }
+            if start + data.len() > self.maxsize {
+                return Err(KyberauthError::MessageTooLarge.into());
+            }
+            message.copy_within(start + data.start..start + data.end, start);
+            message.truncate(start + data.len());
+            if flags & FLAG_FINAL != 0 {
+                return Ok(Received::Message);
+            }
+            index = index.checked_add(1).ok_or(KyberauthError::InvalidRecord("Too many records in a message"))?;
+            socket
+                .read_exact(&mut header)
+                .await
+                .map_err(|e| truncated(e, "Connection closed in the middle of a message"))?;
+        }
+    }
+    /// Decrypt the records of one message. The state is only updated if the whole message is valid.
+    pub(crate) fn decrypt(&mut self, input: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let mut buffer = input.to_vec();
+        self.decryptinplace(&mut buffer, aad)?;
+        Ok(buffer)
+    }
+    /// Replace the records of one message in the buffer by the message. The state is only updated if the whole message
+    /// is valid, the buffer is emptied otherwise.
+    pub(crate) fn decryptinplace(&mut self, buffer: &mut Vec<u8>, aad: &[u8]) -> io::Result<()> {
+        let mut state = self.clone();
+        let result = state.decryptrecords(buffer, aad);
+        match result {
+            Ok(size) => {
+                buffer.truncate(size);
+                *self = state;
+                Ok(())
+            }
+            Err(e) => {
+                buffer.zeroize();
+                Err(e)
+            }
+        }
+    }
+    /// Decrypt every record of the buffer and move their data to its beginning, returns the size of the message.
+    fn decryptrecords(&mut self, buffer: &mut [u8], aad: &[u8]) -> io::Result<usize> {
+        let mut read: usize = 0;
+        let mut written: usize = 0;
+        let mut index: u32 = 0;
+        loop {
+            if buffer.len() - read < HEADERSIZE {
+                return Err(KyberauthError::Truncated("Data ends in the middle of a message").into());
+            }
+            let size = u32::from_be_bytes(buffer[read..read + HEADERSIZE].try_into().unwrap()) as usize;
+            read += HEADERSIZE;
+            if buffer.len() - read < size {
+                return Err(KyberauthError::Truncated("Data ends in the middle of a record").into());
+            }
+            let (flags, data) = self.open(index, &mut buffer[read..read + size], aad)?;
+            if flags & FLAG_CLOSE != 0 {
+                return Err(KyberauthError::InvalidRecord("Unexpected close notify").into());
+            }
+            let data = read + data.start..read + data.end;
+            read += size;
+            //Tickets are only kept when read from the socket
+            if flags & (FLAG_HEARTBEAT | FLAG_TICKET) != 0 {
+                continue;
+            }
+            if written + data.len() > self.maxsize {
+                return Err(KyberauthError::MessageTooLarge.into());
+            }
+            let length = data.len();
+            buffer.copy_within(data, written);
+            written += length;
+            if flags & FLAG_FINAL != 0 {
+                //Nothing may follow the last record
+                if read != buffer.len() {
+                    return Err(KyberauthError::InvalidRecord("Data after the last record").into());
+                }
+                return Ok(written);
+            }
+            index = index.checked_add(1).ok_or(KyberauthError::InvalidRecord("Too many records in a message"))?;
+        }
+    }
+    /// Decrypt the next record in place, without its length header, and returns its flags and where its data is.
+    /// The key is updated after the record if the peer asked for it.
+    pub(crate) fn open(&mut self, index: u32, record: &mut [u8], aad: &[u8]) -> io::Result<(u8, Range<usize>)> {
+        if record.len() < SEQSIZE + TAGSIZE + 1 {
+            return Err(KyberauthError::InvalidRecord("Record too short").into());
+        }
+        let expected = self.seq;
+        let received = u64::from_be_bytes(record[..SEQSIZE].try_into().unwrap());
+        if received < expected {
+            return Err(KyberauthError::Sequence(SequenceError::Replayed { expected, received }).into());
+        }
+        if received > expected {
+            return Err(KyberauthError::Sequence(SequenceError::Gap { expected, received }).into());
+        }
+        let nonce = nonce(&self.iv, received);
+        let body = &mut record[SEQSIZE..];
+        let mut result = withrecordaad(index, aad, |recordaad| self.cipher.decryptinplace(&nonce, recordaad, body));
+        if result.is_err() && !aad.is_empty() {
+            //Control records are sent without associated data whatever the caller expects
+            result = withrecordaad(index, &[], |recordaad| self.cipher.decryptinplace(&nonce, recordaad, body))
+                .and_then(|_| match body[0] & FLAG_CONTROL {
+                    0 => Err(aes_gcm::Error),
+                    _ => Ok(()),
+                });
+        }
+        if result.is_err() {
+            return Err(KyberauthError::Authentication.into());
+        }
+        let flags = body[0];
+        let end = body.len() - TAGSIZE;
+        let mut data = 1..end;
+        if flags & FLAG_PADDED != 0 {
+            if end < 1 + PADHEADER {
+                return Err(KyberauthError::InvalidRecord("Invalid padding").into());
+            }
+            let size = u16::from_be_bytes([body[1], body[2]]) as usize;
+            if size > end - 1 - PADHEADER {
+                return Err(KyberauthError::InvalidRecord("Invalid padding").into());
+            }
+            data = 1 + PADHEADER..1 + PADHEADER + size;
+        }
+        self.seq += 1;
+        if flags & FLAG_KEYUPDATE != 0 {
+            ratchet(self.suite, &mut self.key, &mut self.cipher);
+            self.epoch += 1;
+        }
+        Ok((flags, SEQSIZE + data.start..SEQSIZE + data.end))
+    }
+}
+/// Run an operation within an optional time limit, an expired limit is a `TimedOut` error.
+async fn deadline<F, T>(limit: Option<Duration>, operation: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match limit {
+        Some(limit) => match tokio::time::timeout(limit, operation).await {
+            Ok(result) => result,
+            Err(_) => Err(KyberauthError::TimedOut("Deadline of the connection expired").into()),
+        },
+        None => operation.await,
+    }
+}
+/// An end of stream before the close notify means the data was truncated.
+fn truncated(e: io::Error, reason: &'static str) -> io::Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        return KyberauthError::Truncated(reason).into();
+    }
+    e
+}
+/// Sleep until the given instant, or forever without one.
+async fn sleepuntil(instant: Option<Instant>) {
+    match instant {
+        Some(instant) => tokio::time::sleep_until(instant).await,
+        None => std::future::pending().await,
+    }
+}
+/// Associated data of a record: its index in the message followed by the associated data of the caller.
+/// It stays on the stack without associated data of the caller.
+fn withrecordaad<T>(index: u32, aad: &[u8], f: impl FnOnce(&[u8]) -> T) -> T {
+    if aad.is_empty() {
+        return f(&index.to_be_bytes());
+    }
+    let mut recordaad: Vec<u8> = Vec::with_capacity(4 + aad.len());
+    recordaad.extend_from_slice(&index.to_be_bytes());
+    recordaad.extend_from_slice(aad);
+    f(&recordaad)
+}
+/// Replace a traffic key by the next one and the cipher by one keyed with it. The previous key cannot be computed back
+/// from the new one, and the previous cipher is wiped when it is dropped.
+fn ratchet(suite: CipherSuite, key: &mut [u8; KYBER_SSBYTES], cipher: &mut Cipher) {
+    let mut hasher = Sha3_256::new();
+    hasher.update(KEYUPDATELABEL);
+    hasher.update(&key[..]);
+    key.zeroize();
+    key.copy_from_slice(&hasher.finalize());
+    *cipher = Cipher::new(suite, key);
+}
+/// Build the 96-bit nonce of a record by xoring its sequence number with the nonce base of the direction.
+fn nonce(iv: &[u8; NONCESIZE], seq: u64) -> [u8; NONCESIZE] {
+    let mut nonce = *iv;
+    for (byte, seq) in nonce[NONCESIZE - SEQSIZE..].iter_mut().zip(seq.to_be_bytes()) {
+        *byte ^= seq;
+    }
+    nonce
+}
+/// Split a message in chunks fitting in a record, an empty message still needs one record.
+pub(crate) fn chunks(input: &[u8]) -> Vec<&[u8]> {
+    if input.is_empty() {
+        return vec![input];
+    }
+    input.chunks(CHUNKSIZE).collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn testratchet() {
+        let (key, iv) = ([7u8; KYBER_SSBYTES], [1u8; NONCESIZE]);
+        let mut next = [0u8; KYBER_SSBYTES];
+        next.copy_from_slice(&Sha3_256::new().chain_update(KEYUPDATELABEL).chain_update(key).finalize());
+        for suite in CipherSuite::ALL {
+            let mut tx = SendState::new(suite, key, iv);
+            tx.rekey = RekeyPolicy { records: Some(1), bytes: None, interval: None };
+            tx.encrypt(b"first", &[]).unwrap();
+            let second = tx.encrypt(b"second", &[]).unwrap();
+            //The record after the update is sealed by a cipher keyed with the next key only
+            let mut old = RecvState::new(suite, key, iv);
+            old.seq = 1;
+            assert!(old.decrypt(&second, &[]).is_err());
+            let mut rx = RecvState::new(suite, next, iv);
+            rx.seq = 1;
+            assert_eq!(rx.decrypt(&second, &[]).unwrap(), b"second");
+        }
     }
 }
@@ -1,76 +1,531 @@
+use crate::aes::CipherSuite;
+use crate::early::EarlyFlight;
+use crate::error::{HandshakePhase, KyberauthError};
+use crate::hello::{self, Extension, Negotiated};
+use crate::hybrid::{self, Share, X25519Keypair, SHARESIZE, X25519BYTES};
+use crate::kdf::{Role, Transcript, ACCEPTED, MODEEARLY, MODEFULL, MODERESUME, RANDOMSIZE};
+use crate::key::{AkeClient, Kem, KemKeys};
+use crate::ticket::Ticket;
+use rand::RngCore;
 use safe_pqc_kyber::*;
+use sha3::{Digest, Sha3_256};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{ TcpSocket, TcpStream};
-use std::io::ErrorKind;
-use std::{
-    io,
-    net::SocketAddr,
-};
+use tokio::time::{self, Instant};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-async fn keyhandshake(socket: &mut TcpStream, key: &Keypair) -> io::Result<Vec<u8>> {
+/// Called with the address and the fingerprint of a server missing from `known_hosts`, returns whether to trust it.
+pub type HostKeyCallback = Arc<dyn Fn(SocketAddr, &str) -> bool + Send + Sync>;
+/// How the client checks the key of the server against its `known_hosts` file. The key of a server already in the file
+/// must match, otherwise the handshake fails with `HostKeyMismatch` whatever the policy except `AcceptAny`.
+///
+/// Each line of the file is `host mode fingerprint`, the mode being the name of the key exchange, so a server known in
+/// the hybrid mode is refused with `HostKeyMismatch` in the Kyber only mode and the other way round. Its line must be
+/// removed to change the mode used with it. A trusted server is only added once the key exchange succeeded.
+#[derive(Clone, Default)]
+pub enum HostKeyPolicy {
+    /// Accept any key without reading the file. A man in the middle with its own key is accepted.
+    AcceptAny,
+    /// Refuse servers missing from the file.
+    Strict,
+    /// Trust servers missing from the file and add them to it.
+    #[default]
+    TrustOnFirstUse,
+    /// Ask the callback for servers missing from the file, the ones it trusts are added to it.
+    Callback(HostKeyCallback),
+}
+impl fmt::Debug for HostKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostKeyPolicy::AcceptAny => write!(f, "AcceptAny"),
+            HostKeyPolicy::Strict => write!(f, "Strict"),
+            HostKeyPolicy::TrustOnFirstUse => write!(f, "TrustOnFirstUse"),
+            HostKeyPolicy::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+/// Options of the client side of the handshake
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    suites: Vec<CipherSuite>,
+    extensions: Vec<Extension>,
+    connecttimeout: Option<Duration>,
+    handshaketimeout: Option<Duration>,
+    timeout: Option<Duration>,
+    hostkeys: HostKeyPolicy,
+    knownhosts: PathBuf,
+    hybrid: Option<X25519Keypair>,
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            suites: CipherSuite::ALL.to_vec(),
+            extensions: Vec::new(),
+            connecttimeout: None,
+            handshaketimeout: None,
+            timeout: None,
+            hostkeys: HostKeyPolicy::default(),
+            knownhosts: PathBuf::from("known_hosts"),
+            hybrid: None,
+        }
+    }
+}
+impl ClientConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the cipher suites offered to the server, by order of preference
+    pub fn suites(mut self, suites: &[CipherSuite]) -> Self {
+        self.suites = suites.to_vec();
+        self
+    }
+    pub fn getsuites(&self) -> &[CipherSuite] {
+        &self.suites
+    }
+    /// Set the extensions sent in the hello of the client, see [`crate::hello`].
+    pub fn extensions(mut self, extensions: &[Extension]) -> Self {
+        self.extensions = extensions.to_vec();
+        self
+    }
+    pub fn getextensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+    /// Set the time allowed to open the TCP connection. There is no deadline by default.
+    pub fn connecttimeout(mut self, timeout: Duration) -> Self {
+        self.connecttimeout = Some(timeout);
+        self
+    }
+    pub fn getconnecttimeout(&self) -> Option<Duration> {
+        self.connecttimeout
+    }
+    /// Set the time allowed to the handshake once connected. There is no deadline by default.
+    pub fn handshaketimeout(mut self, timeout: Duration) -> Self {
+        self.handshaketimeout = Some(timeout);
+        self
+    }
+    pub fn gethandshaketimeout(&self) -> Option<Duration> {
+        self.handshaketimeout
+    }
+    /// Set the time allowed to connect and run the handshake, whatever the other deadlines. There is no deadline by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn gettimeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    /// Set how the key of the server is checked, new servers are trusted on first use by default.
+    pub fn hostkeypolicy(mut self, policy: HostKeyPolicy) -> Self {
+        self.hostkeys = policy;
+        self
+    }
+    pub fn gethostkeypolicy(&self) -> &HostKeyPolicy {
+        &self.hostkeys
+    }
+    /// Set the file of known servers, `known_hosts` by default. Each line is the address of a server as `host:port`, the
+    /// mode of the key exchange and the SHA3-256 fingerprint of its key in hex, see [`HostKeyPolicy`].
+    pub fn knownhosts<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.knownhosts = path.as_ref().to_path_buf();
+        self
+    }
+    pub fn getknownhosts(&self) -> &Path {
+        &self.knownhosts
+    }
+    /// Combine Kyber with an X25519 exchange using this key, see [`crate::hybrid`]. The server must accept it.
+    pub fn hybrid(mut self, keys: X25519Keypair) -> Self {
+        self.hybrid = Some(keys);
+        self
+    }
+    pub fn gethybrid(&self) -> Option<&X25519Keypair> {
+        self.hybrid.as_ref()
+    }
+}
+async fn keyhandshake(
+    socket: &mut TcpStream,
+    key: &dyn KemKeys,
+    share: Option<&Share>,
+    clienthello: &[u8],
+    transcript: &mut Transcript,
+) -> Result<(Vec<u8>, Negotiated), KyberauthError> {
     let _ = socket.set_nodelay(true);
-    //The key is sent
+    //The key is sent after the hello and the handshake mode, followed by the X25519 keys in the hybrid mode
+    let mut message = key.getpublic().to_vec();
+    if let Some(share) = share {
+        message.extend_from_slice(share.getmessage());
+    }
     socket.writable().await?;
-    socket.write_all(&key.public).await?;
+    socket.write_all(clienthello).await?;
+    socket.write_all(&[MODEFULL]).await?;
+    socket.write_all(&message).await?;
     socket.flush().await?;
     socket.readable().await?;
-    let mut pubkey: Vec<u8> = Vec::with_capacity(KYBER_PUBLICKEYBYTES);
-    pubkey.clear();
-    pubkey.resize(KYBER_PUBLICKEYBYTES, 0);
+    let negotiated = hello::readserverhello(socket, clienthello, key.getkem(), share.is_some(), transcript).await?;
+    let mut pubkey: Vec<u8> = vec![0; key.getkem().getpublicsize() + share.map_or(0, |_| SHARESIZE)];
     let _ = socket.read_exact(&mut pubkey).await?;
     //The key was read
-    Ok(pubkey)
+    transcript.update([MODEFULL]);
+    transcript.update(&message);
+    transcript.update(&pubkey);
+    Ok((pubkey, negotiated))
+}
+/// Offer the cipher suites and read the one chosen by the server. Both messages are part of the transcript,
+/// so a suite removed from the offer makes the traffic keys differ.
+async fn negotiatesuite(
+    socket: &mut TcpStream,
+    suites: &[CipherSuite],
+    transcript: &mut Transcript,
+) -> Result<CipherSuite, KyberauthError> {
+    let offer = offer(suites)?;
+    socket.writable().await?;
+    socket.write_all(&offer).await?;
+    socket.flush().await?;
+    socket.readable().await?;
+    let mut choice = [0u8; 1];
+    socket.read_exact(&mut choice).await?;
+    transcript.update(&offer);
+    transcript.update(choice);
+    match CipherSuite::fromid(choice[0]) {
+        Some(suite) if suites.contains(&suite) => Ok(suite),
+        _ => Err(KyberauthError::NoCommonSuite),
+    }
+}
+/// Cipher suites offered to the server: the count then the id of each suite
+fn offer(suites: &[CipherSuite]) -> Result<Vec<u8>, KyberauthError> {
+    if suites.is_empty() || suites.len() > u8::MAX as usize {
+        return Err(KyberauthError::InvalidConfig("Invalid cipher suite list"));
+    }
+    let mut offer: Vec<u8> = Vec::with_capacity(1 + suites.len());
+    offer.push(suites.len() as u8);
+    offer.extend(suites.iter().map(|suite| suite.getid()));
+    Ok(offer)
 }
 async fn checkkeys<T>(
     socket: &mut TcpStream,
-    key: &Keypair,
+    key: &dyn KemKeys,
     pubkey: T,
-) -> io::Result<[u8; KYBER_SSBYTES]> where T: AsRef<[u8]> {
-    let pubkey = pubkey.as_ref();
+    transcript: &mut Transcript,
+) -> Result<[u8; KYBER_SSBYTES], KyberauthError> where T: AsRef<[u8]> {
+    let kem = key.getkem();
     let _ = socket.set_nodelay(true);
     socket.writable().await?;
-    let mut rng = rand::thread_rng();
-    let mut alice = Ake::new();
-    let pubkey: [u8; KYBER_PUBLICKEYBYTES] = pubkey[..KYBER_PUBLICKEYBYTES]
-        .try_into().unwrap_or([0;KYBER_PUBLICKEYBYTES]);
-    let client_init = alice.client_init(&pubkey, &mut rng);
-    if pubkey == [0;KYBER_PUBLICKEYBYTES] {
-        return Err(io::Error::from(ErrorKind::InvalidInput));
-    }
+    let (alice, client_init) = AkeClient::init(kem, pubkey.as_ref())?;
     socket.write_all(&client_init).await?;
     socket.flush().await?;
     socket.readable().await?;
-    let mut server_answer: Vec<u8> = Vec::with_capacity(AKE_RESPONSE_BYTES);
-    server_answer.clear();
-    server_answer.resize(AKE_RESPONSE_BYTES, 0);
+    let mut server_answer: Vec<u8> = vec![0; kem.getresponsesize()];
     let _ = socket.read_exact(&mut server_answer).await?;
     //The key was read
-    let server_answer: [u8; AKE_RESPONSE_BYTES] = server_answer[..AKE_RESPONSE_BYTES]
-        .try_into()
-        .unwrap_or([0;AKE_RESPONSE_BYTES]);
-    let result = alice
-        .client_confirm(server_answer, &key.secret);
-    if server_answer == [0;AKE_RESPONSE_BYTES] || result.is_err() {
-        return Err(io::Error::from(ErrorKind::InvalidInput));
-    }
-    Ok(alice.shared_secret)
+    let sharedsecret = alice.confirm(kem, &server_answer, key.getsecret())?;
+    transcript.update(client_init);
+    transcript.update(server_answer);
+    Ok(sharedsecret)
 }
 
-pub async fn connecter(key: &Keypair, addr: SocketAddr) -> io::Result<crate::aes::Connection> {
-
+/// Connect to a server and run the handshake with the default configuration. The key is a `safe_pqc_kyber::Keypair`
+/// or a [`crate::key::KemKeypair`], the server must have a key of the same parameter set.
+pub async fn connecter(key: &dyn KemKeys, addr: SocketAddr) -> Result<crate::aes::Connection, KyberauthError> {
+    connecterwith(key, addr, &ClientConfig::default()).await
+}
+/// Connect to a server and run the handshake
+pub async fn connecterwith(
+    key: &dyn KemKeys,
+    addr: SocketAddr,
+    config: &ClientConfig,
+) -> Result<crate::aes::Connection, KyberauthError> {
+    let kem = key.getkem();
+    let share = config.hybrid.as_ref().map(Share::new);
+    let clienthello = hello::clienthello(&config.extensions, kem, share.is_some())?;
+    let overall = deadline(config.timeout, None);
+    let mut stream = phase(HandshakePhase::Connect, deadline(config.connecttimeout, overall), connect(addr)).await?;
+    let handshake = deadline(config.handshaketimeout, overall);
+    let mut transcript = Transcript::new();
+    let (peer, negotiated) = phase(
+        HandshakePhase::PublicKey,
+        handshake,
+        keyhandshake(&mut stream, key, share.as_ref(), &clienthello, &mut transcript),
+    )
+    .await?;
+    //The key of the server is its Kyber key, followed by its static X25519 key in the hybrid mode
+    let pubkey = &peer[..kem.getpublicsize() + share.as_ref().map_or(0, |_| X25519BYTES)];
+    let newhost = verifyhost(config, addr, pubkey, kem, share.is_some())?;
+    let hexpub=hex::encode(pubkey);
+    let suite = phase(
+        HandshakePhase::SuiteNegotiation,
+        handshake,
+        negotiatesuite(&mut stream, &config.suites, &mut transcript),
+    )
+    .await?;
+    let mut sharedsecret = phase(
+        HandshakePhase::KeyExchange,
+        handshake,
+        checkkeys(&mut stream, key, &peer[..kem.getpublicsize()], &mut transcript),
+    )
+    .await?;
+    if let Some(share) = &share {
+        sharedsecret = share.combine(Role::Client, &peer[kem.getpublicsize()..], &sharedsecret, &transcript.hash())?;
+    }
+    recordhost(config, newhost)?;
+    let peer_addr = stream.peer_addr()?;
+    let mut elem = crate::aes::Connection::new(
+        stream,
+        peer_addr,
+        hexpub,
+        sharedsecret,
+        transcript.hash(),
+        Role::Client,
+        suite,
+    );
+    elem.negotiated = negotiated;
+    Ok(elem)
+}
+/// Resume a session with a ticket issued by the server, without any Kyber operation. Fresh traffic keys are derived from
+/// the secret of the ticket and random values of both sides. Fails with `TicketRejected` if the server does not accept
+/// the ticket anymore, a full handshake with `connecter` is then needed.
+pub async fn resume(ticket: &Ticket, addr: SocketAddr) -> Result<crate::aes::Connection, KyberauthError> {
+    resumewith(ticket, addr, &ClientConfig::default()).await
+}
+/// Same as `resume` with the given configuration, its cipher suites and X25519 key are not used.
+pub async fn resumewith(
+    ticket: &Ticket,
+    addr: SocketAddr,
+    config: &ClientConfig,
+) -> Result<crate::aes::Connection, KyberauthError> {
+    let clienthello = hello::clienthello(&config.extensions, ticket.getkem(), false)?;
+    let overall = deadline(config.timeout, None);
+    let mut stream = phase(HandshakePhase::Connect, deadline(config.connecttimeout, overall), connect(addr)).await?;
+    let handshake = deadline(config.handshaketimeout, overall);
+    let _ = stream.set_nodelay(true);
+    let mut random = [0u8; RANDOMSIZE];
+    rand::thread_rng().fill_bytes(&mut random);
+    let sealed = ticket.getticket();
+    let mut flight: Vec<u8> = Vec::with_capacity(clienthello.len() + 1 + 2 + sealed.len() + RANDOMSIZE);
+    flight.extend_from_slice(&clienthello);
+    flight.push(MODERESUME);
+    flight.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+    flight.extend_from_slice(sealed);
+    flight.extend_from_slice(&random);
+    let mut transcript = Transcript::new();
+    let exchange = async {
+        stream.writable().await?;
+        stream.write_all(&flight).await?;
+        stream.flush().await?;
+        stream.readable().await?;
+        let negotiated = hello::readserverhello(&mut stream, &clienthello, ticket.getkem(), false, &mut transcript).await?;
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status).await?;
+        if status[0] != ACCEPTED {
+            return Err(KyberauthError::TicketRejected);
+        }
+        let mut serverrandom = [0u8; RANDOMSIZE];
+        stream.read_exact(&mut serverrandom).await?;
+        Ok((negotiated, serverrandom))
+    };
+    let (negotiated, serverrandom) = phase(HandshakePhase::Resumption, handshake, exchange).await?;
+    transcript.update([MODERESUME]);
+    transcript.update(sealed);
+    transcript.update(random);
+    transcript.update(serverrandom);
+    let peer_addr = stream.peer_addr()?;
+    let mut elem = crate::aes::Connection::new(
+        stream,
+        peer_addr,
+        ticket.getpeerkey().to_string(),
+        ticket.getsecret(),
+        transcript.hash(),
+        Role::Client,
+        ticket.getciphersuite(),
+    );
+    elem.negotiated = negotiated;
+    Ok(elem)
+}
+/// Connect to a server whose public key is already known and send `data` with the first flight, before the handshake
+/// completes, with the default configuration. See [`connectearlywith`].
+pub async fn connectearly<K, T>(
+    key: &dyn KemKeys,
+    serverkey: K,
+    addr: SocketAddr,
+    data: T,
+) -> Result<(crate::aes::Connection, bool), KyberauthError>
+where
+    K: AsRef<[u8]>,
+    T: AsRef<[u8]>,
+{
+    connectearlywith(key, serverkey, addr, data, &ClientConfig::default()).await
+}
+/// Connect to a server whose public key is already known and send `data` with the first flight, encrypted to the key
+/// of the server with the first suite of the configuration. At most [`crate::early::MAXEARLYDATA`] bytes can be sent.
+/// Returns the connection and whether the server accepted the early data. Rejected early data was dropped and must be
+/// sent again with `senddata` if needed. Early data might be replayed by an attacker, see [`crate::early`].
+/// Fails with `InvalidConfig` in the hybrid mode.
+pub async fn connectearlywith<K, T>(
+    key: &dyn KemKeys,
+    serverkey: K,
+    addr: SocketAddr,
+    data: T,
+    config: &ClientConfig,
+) -> Result<(crate::aes::Connection, bool), KyberauthError>
+where
+    K: AsRef<[u8]>,
+    T: AsRef<[u8]>,
+{
+    let kem = key.getkem();
+    let serverkey = serverkey.as_ref();
+    if serverkey.len() != kem.getpublicsize() {
+        return Err(KyberauthError::InvalidConfig("Invalid public key of the server"));
+    }
+    if config.hybrid.is_some() {
+        return Err(KyberauthError::InvalidConfig("No early data in the hybrid mode"));
+    }
+    let newhost = verifyhost(config, addr, serverkey, kem, false)?;
+    let clienthello = hello::clienthello(&config.extensions, kem, false)?;
+    let offer = offer(&config.suites)?;
+    let flight = EarlyFlight::seal(kem, serverkey, key.getpublic(), config.suites[0], data.as_ref())?;
+    let overall = deadline(config.timeout, None);
+    let mut stream = phase(HandshakePhase::Connect, deadline(config.connecttimeout, overall), connect(addr)).await?;
+    let handshake = deadline(config.handshaketimeout, overall);
+    let _ = stream.set_nodelay(true);
+    let (alice, client_init) = AkeClient::init(kem, serverkey)?;
+    let mut first: Vec<u8> = clienthello.clone();
+    first.push(MODEEARLY);
+    first.extend_from_slice(key.getpublic());
+    first.extend_from_slice(&offer);
+    first.extend_from_slice(&client_init);
+    first.extend_from_slice(&flight.tobytes());
+    let mut transcript = Transcript::new();
+    let exchange = async {
+        stream.writable().await?;
+        stream.write_all(&first).await?;
+        stream.flush().await?;
+        stream.readable().await?;
+        let negotiated = hello::readserverhello(&mut stream, &clienthello, kem, false, &mut transcript).await?;
+        let mut choice = [0u8; 1];
+        stream.read_exact(&mut choice).await?;
+        let suite = match CipherSuite::fromid(choice[0]) {
+            Some(suite) if config.suites.contains(&suite) => suite,
+            _ => return Err(KyberauthError::NoCommonSuite),
+        };
+        let mut server_answer: Vec<u8> = vec![0; kem.getresponsesize()];
+        stream.read_exact(&mut server_answer).await?;
+        let sharedsecret = alice.confirm(kem, &server_answer, key.getsecret())?;
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status).await?;
+        Ok((negotiated, choice, suite, server_answer, sharedsecret, status))
+    };
+    let (negotiated, choice, suite, server_answer, sharedsecret, status) =
+        phase(HandshakePhase::EarlyData, handshake, exchange).await?;
+    recordhost(config, newhost)?;
+    transcript.update([MODEEARLY]);
+    transcript.update(key.getpublic());
+    transcript.update(serverkey);
+    transcript.update(&offer);
+    transcript.update(choice);
+    transcript.update(client_init);
+    transcript.update(server_answer);
+    flight.update(&mut transcript);
+    transcript.update(status);
+    let peer_addr = stream.peer_addr()?;
+    let mut elem = crate::aes::Connection::new(
+        stream,
+        peer_addr,
+        hex::encode(serverkey),
+        sharedsecret,
+        transcript.hash(),
+        Role::Client,
+        suite,
+    );
+    elem.negotiated = negotiated;
+    //Prove that the client holds its secret key before the server uses the early data
+    elem.sendheartbeat().await?;
+    Ok((elem, status[0] == ACCEPTED))
+}
+/// Check the key of the server against the known hosts according to the policy. A known host must be used with the same
+/// key and the same mode, hybrid or not. Returns the line of a new trusted server, added by `recordhost` after the key
+/// exchange.
+fn verifyhost(
+    config: &ClientConfig,
+    addr: SocketAddr,
+    pubkey: &[u8],
+    kem: Kem,
+    hybrid: bool,
+) -> Result<Option<String>, KyberauthError> {
+    if let HostKeyPolicy::AcceptAny = config.hostkeys {
+        return Ok(None);
+    }
+    let mode = if hybrid { hybrid::getname(kem) } else { kem.getname().to_string() };
+    let fingerprint = hex::encode(Sha3_256::digest(pubkey));
+    let host = addr.to_string();
+    let known = match fs::read_to_string(&config.knownhosts) {
+        Ok(known) => known,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut found = false;
+    for line in known.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some(host.as_str()) {
+            continue;
+        }
+        if fields.next() == Some(mode.as_str()) && fields.next() == Some(fingerprint.as_str()) {
+            return Ok(None);
+        }
+        found = true;
+    }
+    if found {
+        return Err(KyberauthError::HostKeyMismatch);
+    }
+    let trusted = match &config.hostkeys {
+        HostKeyPolicy::TrustOnFirstUse => true,
+        HostKeyPolicy::Callback(callback) => callback(addr, &fingerprint),
+        _ => false,
+    };
+    if !trusted {
+        return Err(KyberauthError::UnknownHost);
+    }
+    Ok(Some(format!("{} {} {}", host, mode, fingerprint)))
+}
+/// Add a new trusted server to the known hosts, once it proved that it holds its key
+fn recordhost(config: &ClientConfig, line: Option<String>) -> Result<(), KyberauthError> {
+    if let Some(line) = line {
+        let mut file = OpenOptions::new().create(true).append(true).open(&config.knownhosts)?;
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+async fn connect(addr: SocketAddr) -> Result<TcpStream, KyberauthError> {
     let socket = TcpSocket::new_v4()?;
     if cfg!(unix) {
         socket.set_reuseport(false)?;
     }
     socket.set_reuseaddr(false)?;
-    let mut stream: TcpStream = socket.connect(addr).await?; //TODO: Implements a timeout
-    let pubkey = keyhandshake(&mut stream, key).await?;
-    let hexpub=hex::encode(pubkey.clone());
-    let sharedsecret = checkkeys(&mut stream, key, &pubkey).await?;
-    let peer_addr = stream.peer_addr();
-    if peer_addr.is_err() {
-        return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
-    }
-    let elem = crate::aes::Connection::new(stream, peer_addr.unwrap(), hexpub, sharedsecret);
-    Ok(elem)
+    let stream: TcpStream = socket.connect(addr).await?;
+    Ok(stream)
+}
+/// Deadline of a timeout starting now, never later than the overall deadline
+fn deadline(timeout: Option<Duration>, overall: Option<Instant>) -> Option<Instant> {
+    match (timeout.map(|timeout| Instant::now() + timeout), overall) {
+        (Some(deadline), Some(overall)) => Some(deadline.min(overall)),
+        (deadline, overall) => deadline.or(overall),
+    }
+}
+/// Run a phase of the handshake, fails with `HandshakeTimeout` naming the phase if the deadline expires first.
+async fn phase<F, T>(phase: HandshakePhase, deadline: Option<Instant>, future: F) -> Result<T, KyberauthError>
+where
+    F: Future<Output = Result<T, KyberauthError>>,
+{
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| KyberauthError::HandshakeTimeout(phase))?,
+        None => future.await,
+    }
 }
@@ -0,0 +1,104 @@
+//! Cover traffic: records are sent at a constant rate and padded to the same size, so idle and active periods
+//! look the same on the wire. When no message is waiting, a heartbeat is sent instead, the peer skips it.
+use crate::aes::{PaddingPolicy, CHUNKSIZE, FLAG_FINAL};
+use crate::error::KyberauthError;
+use crate::split::EncryptedWriter;
+use std::io;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+//Messages waiting to be sent
+const QUEUESIZE: usize = 16;
+struct Job {
+    data: Vec<u8>,
+    sent: usize,
+    index: u32,
+    done: oneshot::Sender<Result<(), KyberauthError>>,
+}
+/// Sending half of a connection that writes exactly one record every interval, spawned on the tokio runtime.
+/// A record carries up to 10000 bytes, so the throughput is limited to 10000 bytes per interval.
+#[derive(Debug)]
+pub struct CoverWriter {
+    queue: mpsc::Sender<Job>,
+    task: JoinHandle<Result<(), KyberauthError>>,
+    maxsize: usize,
+}
+impl CoverWriter {
+    /// Start sending records from the writer every `interval`. Every record is padded to the maximum size.
+    pub fn new(mut writer: EncryptedWriter, interval: Duration) -> Self {
+        writer.setpaddingpolicy(PaddingPolicy::Constant);
+        let maxsize = writer.getmaxsize();
+        let (queue, jobs) = mpsc::channel(QUEUESIZE);
+        CoverWriter {
+            queue,
+            task: tokio::spawn(cover(writer, interval, jobs)),
+            maxsize,
+        }
+    }
+    /// Queue a message and wait until its last record is sent. Fails with `Closed` once the cover traffic stopped.
+    pub async fn senddata<T>(&mut self, text: T) -> Result<(), KyberauthError> where T: AsRef<[u8]> {
+        if text.as_ref().len() > self.maxsize {
+            return Err(KyberauthError::MessageTooLarge);
+        }
+        let (done, result) = oneshot::channel();
+        let job = Job {
+            data: text.as_ref().to_vec(),
+            sent: 0,
+            index: 0,
+            done,
+        };
+        self.queue.send(job).await.map_err(|_| KyberauthError::Closed)?;
+        result.await.map_err(|_| KyberauthError::Closed)?
+    }
+    /// Send the queued messages, then the close notify, and stop the cover traffic. Fails with `Closed` if the error that
+    /// stopped it was already returned by `senddata`.
+    pub async fn clean(self) -> Result<(), KyberauthError> {
+        drop(self.queue);
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(KyberauthError::Io(io::Error::other(e))),
+        }
+    }
+}
+/// Send one record per tick, from the current message or a heartbeat if there is none.
+async fn cover(
+    mut writer: EncryptedWriter,
+    interval: Duration,
+    mut jobs: mpsc::Receiver<Job>,
+) -> Result<(), KyberauthError> {
+    let mut ticks = time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut current: Option<Job> = None;
+    loop {
+        ticks.tick().await;
+        if current.is_none() {
+            current = match jobs.try_recv() {
+                Ok(job) => Some(job),
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    writer.sendheartbeat().await?;
+                    continue;
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => return writer.clean().await,
+            };
+        }
+        let mut job = current.take().unwrap();
+        let end = job.data.len().min(job.sent + CHUNKSIZE);
+        let flags = if end == job.data.len() { FLAG_FINAL } else { 0 };
+        let result = writer.sendrecord(job.index, flags, &job.data[job.sent..end]).await;
+        if let Err(e) = result {
+            //The error goes to the waiting message, or to clean if nobody waits for it anymore
+            return match job.done.send(Err(e.into())) {
+                Ok(()) => Err(KyberauthError::Closed),
+                Err(result) => result,
+            };
+        }
+        if flags == FLAG_FINAL {
+            let _ = job.done.send(Ok(()));
+        } else {
+            job.sent = end;
+            job.index += 1;
+            current = Some(job);
+        }
+    }
+}
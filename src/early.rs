@@ -0,0 +1,181 @@
+//! Early data: a client that already knows the public key of the server sends application data in its first flight,
+//! encrypted to the server key, so the server gets it without waiting for the handshake to complete.
+//!
+//! Early data has weaker guarantees than the rest of the connection. An attacker who recorded the first flight can
+//! replay it: the server rejects replays of flights seen within its [`ReplayWindow`], and flights older than the window,
+//! but a server with several instances or restarted in the window might accept it again. Only send requests that can
+//! safely be processed twice as early data.
+//!
+//! The early keys only come from the key of the server, anyone knowing the public key of a client can send early data
+//! in its name. The server keeps the early data until the first record of the client, a heartbeat sent right after the
+//! handshake, proves that the client holds its secret key. A client that does not prove it fails the handshake.
+use crate::aes::{CipherSuite, RecvState, SendState, CHUNKSIZE, HEADERSIZE, RECORDSIZE};
+use crate::error::KyberauthError;
+use crate::kdf::{Transcript, TrafficSecrets, HASHSIZE, MODEEARLY};
+use crate::key::{self, Kem, KemKeys};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+/// Maximum size of the early data of a connection.
+pub const MAXEARLYDATA: usize = 16 * 1024;
+//Records of the largest early data
+const MAXRECORDS: usize = MAXEARLYDATA + MAXEARLYDATA.div_ceil(CHUNKSIZE) * (HEADERSIZE + RECORDSIZE - CHUNKSIZE);
+//Time the client sent the flight in milliseconds and cipher suite of the early data
+const HEADER: usize = 8 + 1;
+/// Early data received by the server, see [`crate::aes::Connection::takeearlydata`].
+/// The same bytes might have been received before by another connection, they are not protected against replay
+/// like the rest of the connection.
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct EarlyData(Vec<u8>);
+impl EarlyData {
+    /// Get the early data, it might be a replay.
+    pub fn replayable(&self) -> &[u8] {
+        &self.0
+    }
+    /// Get the early data, it might be a replay.
+    pub fn into_replayable(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.0)
+    }
+}
+/// Anti-replay state of a server accepting early data, shared by its listeners. Early data is accepted if the client sent
+/// it less than `window` ago, according to the clocks of both sides, and if the same flight was not seen in the window.
+/// Every flight accepted is remembered for the length of the window.
+#[derive(Debug)]
+pub struct ReplayWindow {
+    window: Duration,
+    //Hash of each flight accepted and when it leaves the window, in milliseconds
+    seen: Mutex<HashMap<[u8; HASHSIZE], u64>>,
+}
+impl ReplayWindow {
+    pub fn new(window: Duration) -> Self {
+        ReplayWindow {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn getwindow(&self) -> Duration {
+        self.window
+    }
+    /// Returns true if the flight was sent within the window and was not seen before.
+    fn check(&self, timestamp: u64, flight: [u8; HASHSIZE]) -> bool {
+        let now = now();
+        let window = self.window.as_millis() as u64;
+        if timestamp.abs_diff(now) > window {
+            return false;
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expiry| *expiry >= now);
+        seen.insert(flight, timestamp.saturating_add(window)).is_none()
+    }
+}
+/// Early data of the first flight of the client: a ciphertext encapsulated to the server key, the time and suite of the
+/// early data and its records. The records are encrypted with keys derived from the encapsulated secret.
+pub(crate) struct EarlyFlight {
+    ciphertext: Vec<u8>,
+    header: [u8; HEADER],
+    records: Vec<u8>,
+}
+impl EarlyFlight {
+    /// Encrypt early data to the key of the server, of the parameter set `kem`.
+    pub(crate) fn seal(
+        kem: Kem,
+        serverkey: &[u8],
+        clientkey: &[u8],
+        suite: CipherSuite,
+        data: &[u8],
+    ) -> Result<Self, KyberauthError> {
+        if data.len() > MAXEARLYDATA {
+            return Err(KyberauthError::MessageTooLarge);
+        }
+        let (ciphertext, mut secret) = key::encapsulate(kem, serverkey)?;
+        let mut flight = EarlyFlight {
+            ciphertext,
+            header: [0; HEADER],
+            records: Vec::new(),
+        };
+        flight.header[..8].copy_from_slice(&now().to_be_bytes());
+        flight.header[8] = suite.getid();
+        let secrets = TrafficSecrets::new(&secret, &flight.hash(clientkey, serverkey));
+        secret.zeroize();
+        let mut state = SendState::new(suite, secrets.clientkey, secrets.clientiv);
+        flight.records = state.encrypt(data, &[])?;
+        Ok(flight)
+    }
+    /// Read the early data sent by the client after its handshake messages, encapsulated to a key of the parameter set.
+    pub(crate) async fn read<R>(socket: &mut R, kem: Kem) -> Result<Self, KyberauthError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut flight = EarlyFlight {
+            ciphertext: vec![0; kem.getciphertextsize()],
+            header: [0; HEADER],
+            records: Vec::new(),
+        };
+        socket.read_exact(&mut flight.ciphertext).await?;
+        socket.read_exact(&mut flight.header).await?;
+        let mut length = [0u8; 4];
+        socket.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAXRECORDS {
+            return Err(KyberauthError::Handshake("Early data too large"));
+        }
+        flight.records.resize(length, 0);
+        socket.read_exact(&mut flight.records).await?;
+        Ok(flight)
+    }
+    /// Serialize the flight as sent on the wire.
+    pub(crate) fn tobytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(self.ciphertext.len() + HEADER + 4 + self.records.len());
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes.extend_from_slice(&self.header);
+        bytes.extend_from_slice(&(self.records.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.records);
+        bytes
+    }
+    /// Decrypt the early data with the secret key of the server. Returns `None` if the early data is rejected: no replay
+    /// window, suite not accepted, replayed or too old flight, or records that do not decrypt.
+    pub(crate) fn open(
+        &self,
+        key: &dyn KemKeys,
+        clientkey: &[u8],
+        suites: &[CipherSuite],
+        window: Option<&ReplayWindow>,
+    ) -> Option<EarlyData> {
+        let window = window?;
+        let suite = CipherSuite::fromid(self.header[8]).filter(|suite| suites.contains(suite))?;
+        let mut secret = key::decapsulate(key.getkem(), &self.ciphertext, key.getsecret()).ok()?;
+        let hash = self.hash(clientkey, key.getpublic());
+        let secrets = TrafficSecrets::new(&secret, &hash);
+        secret.zeroize();
+        let mut state = RecvState::new(suite, secrets.clientkey, secrets.clientiv);
+        state.maxsize = MAXEARLYDATA;
+        let data = state.decrypt(&self.records, &[]).ok()?;
+        let timestamp = u64::from_be_bytes(self.header[..8].try_into().unwrap());
+        if !window.check(timestamp, hash) {
+            return None;
+        }
+        Some(EarlyData(data))
+    }
+    /// Add the flight to the transcript of the handshake.
+    pub(crate) fn update(&self, transcript: &mut Transcript) {
+        transcript.update(&self.ciphertext);
+        transcript.update(self.header);
+        transcript.update(&self.records);
+    }
+    /// Hash binding the early keys to both public keys, the ciphertext and the header.
+    fn hash(&self, clientkey: &[u8], serverkey: &[u8]) -> [u8; HASHSIZE] {
+        let mut transcript = Transcript::new();
+        transcript.update([MODEEARLY]);
+        transcript.update(clientkey);
+        transcript.update(serverkey);
+        transcript.update(&self.ciphertext);
+        transcript.update(self.header);
+        transcript.hash()
+    }
+}
+/// Milliseconds since the epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64)
+}
@@ -0,0 +1,237 @@
+//! Errors of the handshake and of the record layer. Every failure has its own variant, so a caller can tell a key
+//! that is not authorized from a failed key exchange or a reset socket.
+//!
+//! A `KyberauthError` converts to an `io::Error` of the matching kind and back without losing its variant,
+//! so it can go through code working with `io::Result`, such as [`crate::stream::EncryptedStream`].
+use crate::aes::SequenceError;
+use safe_pqc_kyber::KyberError;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+/// Error of the library.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum KyberauthError {
+    /// Error of the socket or of the file system.
+    Io(io::Error),
+    /// The configuration cannot be used, such as an empty list of cipher suites.
+    InvalidConfig(&'static str),
+    /// The key file does not have the expected format.
+    InvalidKeyFile(&'static str),
+    /// The public key and the secret key of a key pair do not match.
+    KeyMismatch,
+    /// A Kyber operation failed on the given keys.
+    Kyber(KyberError),
+    /// The key of the peer is not in `authorized_keys`.
+    UnauthorizedPeer,
+    /// The server is not in `known_hosts` and the host key policy does not trust it.
+    UnknownHost,
+    /// The key of the server does not match the one in `known_hosts`, the connection might be intercepted.
+    HostKeyMismatch,
+    /// The client and the server have no cipher suite in common.
+    NoCommonSuite,
+    /// The client and the server have no protocol version in common.
+    NoCommonVersion,
+    /// The client and the server are not built with the same Kyber parameter set.
+    NoCommonKem,
+    /// A handshake message is malformed.
+    Handshake(&'static str),
+    /// The server does not accept the resumption ticket anymore, a full handshake is needed.
+    TicketRejected,
+    /// The authenticated key exchange failed, the peer does not own its key or the handshake was tampered with.
+    KeyExchange(KyberError),
+    /// A record failed authentication: wrong key, wrong associated data or tampered data.
+    Authentication,
+    /// A record does not carry the expected sequence number.
+    Sequence(SequenceError),
+    /// A record is malformed or out of place.
+    InvalidRecord(&'static str),
+    /// The message exceeds the maximum size of the connection.
+    MessageTooLarge,
+    /// The data or the connection ended in the middle of a message, or the peer closed without close notify.
+    Truncated(&'static str),
+    /// The connection was closed with `clean`, nothing can be sent anymore.
+    Closed,
+    /// A deadline of the keepalive policy expired.
+    TimedOut(&'static str),
+    /// A deadline of the client configuration expired during this phase of the handshake.
+    HandshakeTimeout(HandshakePhase),
+    /// Every sequence number was used, a new connection is needed.
+    SequenceExhausted,
+    /// The AEAD could not encrypt the record.
+    Encryption,
+    /// Too much keying material was requested from the exporter.
+    ExportTooLong,
+    /// A file transfer message is malformed or the file cannot be sent.
+    FileTransfer(&'static str),
+    /// The file received does not match the digest sent with it.
+    DigestMismatch,
+    /// Every stream identifier of the session was used, a new session is needed.
+    StreamIdsExhausted,
+    /// The stream was closed with `close`, nothing can be sent on it anymore.
+    StreamClosed,
+    /// The stream was reset by the peer, or locally because the peer broke its limits.
+    StreamReset(&'static str),
+}
+/// Phase of the handshake of a client, named by `HandshakeTimeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HandshakePhase {
+    /// Opening the TCP connection.
+    Connect,
+    /// Exchange of the hellos and of the public keys.
+    PublicKey,
+    /// Choice of the cipher suite.
+    SuiteNegotiation,
+    /// Authenticated key exchange.
+    KeyExchange,
+    /// Resumption with a ticket.
+    Resumption,
+    /// Handshake carrying early data.
+    EarlyData,
+}
+impl fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakePhase::Connect => write!(f, "connect"),
+            HandshakePhase::PublicKey => write!(f, "public key exchange"),
+            HandshakePhase::SuiteNegotiation => write!(f, "cipher suite negotiation"),
+            HandshakePhase::KeyExchange => write!(f, "key exchange"),
+            HandshakePhase::Resumption => write!(f, "resumption"),
+            HandshakePhase::EarlyData => write!(f, "early data handshake"),
+        }
+    }
+}
+impl KyberauthError {
+    /// Get the kind of `io::Error` matching this error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            KyberauthError::Io(e) => e.kind(),
+            KyberauthError::InvalidConfig(_) | KyberauthError::ExportTooLong => ErrorKind::InvalidInput,
+            KyberauthError::UnauthorizedPeer
+            | KyberauthError::UnknownHost
+            | KyberauthError::HostKeyMismatch
+            | KyberauthError::TicketRejected => ErrorKind::PermissionDenied,
+            KyberauthError::Truncated(_) => ErrorKind::UnexpectedEof,
+            KyberauthError::Closed | KyberauthError::StreamClosed => ErrorKind::BrokenPipe,
+            KyberauthError::StreamReset(_) => ErrorKind::ConnectionReset,
+            KyberauthError::TimedOut(_) | KyberauthError::HandshakeTimeout(_) => ErrorKind::TimedOut,
+            KyberauthError::SequenceExhausted | KyberauthError::StreamIdsExhausted | KyberauthError::Encryption => {
+                ErrorKind::Other
+            }
+            _ => ErrorKind::InvalidData,
+        }
+    }
+    /// Copy of the error for one more receiver. An `io::Error` cannot be copied, only its kind and message are kept.
+    pub(crate) fn duplicate(&self) -> KyberauthError {
+        let kyber = |e: &KyberError| match e {
+            KyberError::InvalidInput => KyberError::InvalidInput,
+            KyberError::Decapsulation => KyberError::Decapsulation,
+        };
+        match self {
+            KyberauthError::Io(e) => KyberauthError::Io(io::Error::new(e.kind(), e.to_string())),
+            KyberauthError::InvalidConfig(reason) => KyberauthError::InvalidConfig(reason),
+            KyberauthError::InvalidKeyFile(reason) => KyberauthError::InvalidKeyFile(reason),
+            KyberauthError::KeyMismatch => KyberauthError::KeyMismatch,
+            KyberauthError::Kyber(e) => KyberauthError::Kyber(kyber(e)),
+            KyberauthError::UnauthorizedPeer => KyberauthError::UnauthorizedPeer,
+            KyberauthError::UnknownHost => KyberauthError::UnknownHost,
+            KyberauthError::HostKeyMismatch => KyberauthError::HostKeyMismatch,
+            KyberauthError::NoCommonSuite => KyberauthError::NoCommonSuite,
+            KyberauthError::NoCommonVersion => KyberauthError::NoCommonVersion,
+            KyberauthError::NoCommonKem => KyberauthError::NoCommonKem,
+            KyberauthError::Handshake(reason) => KyberauthError::Handshake(reason),
+            KyberauthError::TicketRejected => KyberauthError::TicketRejected,
+            KyberauthError::KeyExchange(e) => KyberauthError::KeyExchange(kyber(e)),
+            KyberauthError::Authentication => KyberauthError::Authentication,
+            KyberauthError::Sequence(e) => KyberauthError::Sequence(*e),
+            KyberauthError::InvalidRecord(reason) => KyberauthError::InvalidRecord(reason),
+            KyberauthError::MessageTooLarge => KyberauthError::MessageTooLarge,
+            KyberauthError::Truncated(reason) => KyberauthError::Truncated(reason),
+            KyberauthError::Closed => KyberauthError::Closed,
+            KyberauthError::TimedOut(reason) => KyberauthError::TimedOut(reason),
+            KyberauthError::HandshakeTimeout(phase) => KyberauthError::HandshakeTimeout(*phase),
+            KyberauthError::SequenceExhausted => KyberauthError::SequenceExhausted,
+            KyberauthError::Encryption => KyberauthError::Encryption,
+            KyberauthError::ExportTooLong => KyberauthError::ExportTooLong,
+            KyberauthError::FileTransfer(reason) => KyberauthError::FileTransfer(reason),
+            KyberauthError::DigestMismatch => KyberauthError::DigestMismatch,
+            KyberauthError::StreamIdsExhausted => KyberauthError::StreamIdsExhausted,
+            KyberauthError::StreamClosed => KyberauthError::StreamClosed,
+            KyberauthError::StreamReset(reason) => KyberauthError::StreamReset(reason),
+        }
+    }
+}
+impl fmt::Display for KyberauthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KyberauthError::Io(e) => write!(f, "I/O error: {}", e),
+            KyberauthError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+            KyberauthError::InvalidKeyFile(reason) => write!(f, "Invalid key file: {}", reason),
+            KyberauthError::KeyMismatch => write!(f, "Public key and secret key do not match"),
+            KyberauthError::Kyber(e) => write!(f, "Kyber error: {}", e),
+            KyberauthError::UnauthorizedPeer => write!(f, "Peer key not found in authorized_keys"),
+            KyberauthError::UnknownHost => write!(f, "Server not found in known_hosts"),
+            KyberauthError::HostKeyMismatch => write!(f, "Server key does not match known_hosts"),
+            KyberauthError::NoCommonSuite => write!(f, "No common cipher suite"),
+            KyberauthError::NoCommonVersion => write!(f, "No common protocol version"),
+            KyberauthError::NoCommonKem => write!(f, "No common Kyber parameter set"),
+            KyberauthError::Handshake(reason) => write!(f, "Invalid handshake: {}", reason),
+            KyberauthError::TicketRejected => write!(f, "Resumption ticket rejected"),
+            KyberauthError::KeyExchange(e) => write!(f, "Key exchange failed: {}", e),
+            KyberauthError::Authentication => write!(f, "Record authentication failed"),
+            KyberauthError::Sequence(e) => write!(f, "{}", e),
+            KyberauthError::InvalidRecord(reason) => write!(f, "Invalid record: {}", reason),
+            KyberauthError::MessageTooLarge => write!(f, "Message exceeds the maximum size"),
+            KyberauthError::Truncated(reason) => write!(f, "{}", reason),
+            KyberauthError::Closed => write!(f, "Connection closed"),
+            KyberauthError::TimedOut(reason) => write!(f, "{}", reason),
+            KyberauthError::HandshakeTimeout(phase) => write!(f, "Handshake timed out during {}", phase),
+            KyberauthError::SequenceExhausted => write!(f, "No sequence number left"),
+            KyberauthError::Encryption => write!(f, "Record encryption failed"),
+            KyberauthError::ExportTooLong => write!(f, "Too much keying material requested"),
+            KyberauthError::FileTransfer(reason) => write!(f, "Invalid file transfer: {}", reason),
+            KyberauthError::DigestMismatch => write!(f, "File digest does not match"),
+            KyberauthError::StreamIdsExhausted => write!(f, "No stream identifier left"),
+            KyberauthError::StreamClosed => write!(f, "Stream closed"),
+            KyberauthError::StreamReset(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+impl Error for KyberauthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KyberauthError::Io(e) => Some(e),
+            KyberauthError::Kyber(e) | KyberauthError::KeyExchange(e) => Some(e),
+            KyberauthError::Sequence(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for KyberauthError {
+    /// Get back the error wrapped in an `io::Error` by the library, other errors become `Io`.
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<KyberauthError>()) {
+            return *e.into_inner().unwrap().downcast::<KyberauthError>().unwrap();
+        }
+        KyberauthError::Io(e)
+    }
+}
+impl From<KyberauthError> for io::Error {
+    fn from(e: KyberauthError) -> Self {
+        match e {
+            KyberauthError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+impl From<KyberError> for KyberauthError {
+    fn from(e: KyberError) -> Self {
+        KyberauthError::Kyber(e)
+    }
+}
+impl From<SequenceError> for KyberauthError {
+    fn from(e: SequenceError) -> Self {
+        KyberauthError::Sequence(e)
+    }
+}
@@ -0,0 +1,150 @@
+//! Transfer of files over a connection. The file is sent in chunks of [`FILECHUNK`] bytes, each one a message
+//! authenticated by the record layer, then checked against the SHA3-256 digest announced by the sender.
+//!
+//! The receiver writes the file to `<name>.<digest>.part` in its directory, where `<digest>` is the hex of the first
+//! 8 bytes of the digest, and renames it to `<name>` once the digest matches. An interrupted transfer is resumed by
+//! calling `send_file` and `receive_file` again, over a new connection: the receiver acknowledges the bytes already in
+//! its partial file with their digest, and the sender starts from this offset if they match the start of the file,
+//! from the beginning otherwise. Files are read and hashed without blocking the runtime.
+use crate::aes::Connection;
+use crate::error::KyberauthError;
+use crate::kdf::HASHSIZE;
+use sha3::{Digest, Sha3_256};
+use std::ffi::OsStr;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+/// Size of the chunks of a file
+pub const FILECHUNK: usize = 64 * 1024;
+//Answer of the receiver once the file is checked
+const VALID: u8 = 1;
+const INVALID: u8 = 0;
+const CLOSED: &str = "Connection closed during the file transfer";
+impl Connection {
+    /// Send a file to the peer, which receives it with `receive_file`. Only the name of the file is sent, not its directory.
+    /// Returns the number of bytes sent, less than the size of the file if a previous transfer is resumed.
+    /// Fails with `DigestMismatch` if the file received does not match the digest.
+    pub async fn send_file<P>(&mut self, path: P) -> Result<u64, KyberauthError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(KyberauthError::FileTransfer("Invalid file name"))?;
+        let mut file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let filedigest = digest(path, size).await?;
+        //Size, digest and name of the file
+        let mut metadata: Vec<u8> = Vec::with_capacity(8 + HASHSIZE + name.len());
+        metadata.extend_from_slice(&size.to_be_bytes());
+        metadata.extend_from_slice(&filedigest);
+        metadata.extend_from_slice(name.as_bytes());
+        self.senddata(&metadata).await?;
+        //Bytes already received and their digest
+        let ack = self.receivedata().await?.ok_or(KyberauthError::Truncated(CLOSED))?;
+        if ack.len() != 8 + HASHSIZE {
+            return Err(KyberauthError::FileTransfer("Invalid acknowledgment"));
+        }
+        let mut offset = u64::from_be_bytes(ack[..8].try_into().unwrap());
+        if offset > size || (offset > 0 && digest(path, offset).await? != ack[8..]) {
+            offset = 0;
+        }
+        self.senddata(offset.to_be_bytes()).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut buffer = vec![0u8; FILECHUNK];
+        let mut remaining = size - offset;
+        while remaining > 0 {
+            let length = remaining.min(FILECHUNK as u64) as usize;
+            file.read_exact(&mut buffer[..length]).await?;
+            self.senddata(&buffer[..length]).await?;
+            remaining -= length as u64;
+        }
+        let status = self.receivedata().await?.ok_or(KyberauthError::Truncated(CLOSED))?;
+        if status != [VALID] {
+            return Err(KyberauthError::DigestMismatch);
+        }
+        Ok(size - offset)
+    }
+    /// Receive a file sent with `send_file` in the directory and return its path. An existing file of the same name is
+    /// replaced. Fails with `DigestMismatch` if the file does not match the digest, the partial file is then removed.
+    pub async fn receive_file<P>(&mut self, dir: P) -> Result<PathBuf, KyberauthError>
+    where
+        P: AsRef<Path>,
+    {
+        let metadata = self.receivedata().await?.ok_or(KyberauthError::Truncated(CLOSED))?;
+        if metadata.len() <= 8 + HASHSIZE {
+            return Err(KyberauthError::FileTransfer("Invalid file metadata"));
+        }
+        let size = u64::from_be_bytes(metadata[..8].try_into().unwrap());
+        let expected = &metadata[8..8 + HASHSIZE];
+        //The name must not lead outside of the directory
+        let name = std::str::from_utf8(&metadata[8 + HASHSIZE..])
+            .ok()
+            .filter(|name| Path::new(name).file_name() == Some(OsStr::new(name)))
+            .ok_or(KyberauthError::FileTransfer("Invalid file name"))?;
+        let dir = dir.as_ref();
+        let target = dir.join(name);
+        let partial = dir.join(format!("{}.{}.part", name, hex::encode(&expected[..8])));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&partial)
+            .await?;
+        let existing = file.metadata().await?.len();
+        let existing = if existing > size { 0 } else { existing };
+        let mut ack = existing.to_be_bytes().to_vec();
+        ack.extend_from_slice(&digest(&partial, existing).await?);
+        self.senddata(&ack).await?;
+        //The sender starts over if the partial file is not the start of its file
+        let offset = self.receivedata().await?.ok_or(KyberauthError::Truncated(CLOSED))?;
+        let mut offset = offset
+            .try_into()
+            .map(u64::from_be_bytes)
+            .ok()
+            .filter(|offset| *offset == existing || *offset == 0)
+            .ok_or(KyberauthError::FileTransfer("Invalid offset"))?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        while offset < size {
+            let chunk = self.receivedata().await?.ok_or(KyberauthError::Truncated(CLOSED))?;
+            if chunk.is_empty() || chunk.len() as u64 > size - offset {
+                return Err(KyberauthError::FileTransfer("Invalid chunk size"));
+            }
+            file.write_all(&chunk).await?;
+            offset += chunk.len() as u64;
+        }
+        file.sync_all().await?;
+        drop(file);
+        let valid = digest(&partial, size).await? == expected;
+        self.senddata([if valid { VALID } else { INVALID }]).await?;
+        if !valid {
+            fs::remove_file(&partial).await?;
+            return Err(KyberauthError::DigestMismatch);
+        }
+        fs::rename(&partial, &target).await?;
+        Ok(target)
+    }
+}
+/// SHA3-256 digest of the first `length` bytes of the file, computed on a blocking thread
+async fn digest(path: &Path, length: u64) -> io::Result<[u8; HASHSIZE]> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?.take(length);
+        let mut hasher = Sha3_256::new();
+        let mut buffer = vec![0u8; FILECHUNK];
+        loop {
+            let size = file.read(&mut buffer)?;
+            if size == 0 {
+                break;
+            }
+            hasher.update(&buffer[..size]);
+        }
+        Ok(hasher.finalize().into())
+    })
+    .await?
+}
@@ -0,0 +1,212 @@
+//! Hello messages exchanged before the handshake. The client sends the magic bytes, the protocol versions it supports,
+//! the parameter set of its key and its extensions, the server answers with the magic bytes, the chosen version, the
+//! parameter set if its key has the same one, see [`crate::key`], and its own extensions.
+//!
+//! Both hellos are added to the handshake transcript, so a peer whose hello was modified on the way, to force an older
+//! version or remove an extension, derives different traffic keys and cannot read the connection.
+//! Extensions are not interpreted by the library, each side gets the extensions of its peer with `getpeerextensions`.
+use crate::error::KyberauthError;
+use crate::kdf::Transcript;
+use crate::key::Kem;
+use std::collections::HashSet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+/// First bytes sent by each side.
+pub const MAGIC: [u8; 4] = *b"KYBA";
+/// Version of the protocol implemented by this crate.
+pub const VERSION: u16 = 1;
+/// Versions supported by this crate, by order of preference.
+pub const VERSIONS: [u16; 1] = [VERSION];
+//Flag of the parameter set id when X25519 is combined with Kyber, see crate::hybrid
+const HYBRID: u8 = 0x80;
+/// Extension sent in a hello message: a type and opaque data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+impl Extension {
+    pub fn new<T>(kind: u16, data: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        Extension { kind, data: data.into() }
+    }
+}
+/// Version and parameter set chosen by the server, whether the exchange is hybrid and extensions sent by the peer.
+#[derive(Debug, Clone)]
+pub(crate) struct Negotiated {
+    pub(crate) version: u16,
+    pub(crate) kem: Kem,
+    pub(crate) hybrid: bool,
+    pub(crate) extensions: Vec<Extension>,
+}
+impl Default for Negotiated {
+    fn default() -> Self {
+        Negotiated {
+            version: VERSION,
+            kem: Kem::CURRENT,
+            hybrid: false,
+            extensions: Vec::new(),
+        }
+    }
+}
+/// Hello of the client as read by the server
+pub(crate) struct ClientHello {
+    pub(crate) raw: Vec<u8>,
+    //First version and parameter set id of the client supported by the server
+    pub(crate) version: Option<u16>,
+    pub(crate) kem: Option<u8>,
+    pub(crate) extensions: Vec<Extension>,
+}
+/// Id of the parameter set sent in the hellos, combined with X25519 or not
+pub(crate) fn kemid(kem: Kem, hybrid: bool) -> u8 {
+    kem.getid() | if hybrid { HYBRID } else { 0 }
+}
+/// Hello of the client: magic, count of versions, versions, count of parameter sets, parameter sets, length of the
+/// extensions and extensions.
+pub(crate) fn clienthello(extensions: &[Extension], kem: Kem, hybrid: bool) -> Result<Vec<u8>, KyberauthError> {
+    let mut hello: Vec<u8> = Vec::new();
+    hello.extend_from_slice(&MAGIC);
+    hello.push(VERSIONS.len() as u8);
+    for version in VERSIONS {
+        hello.extend_from_slice(&version.to_be_bytes());
+    }
+    hello.push(1);
+    hello.push(kemid(kem, hybrid));
+    hello.extend_from_slice(&encode(extensions)?);
+    Ok(hello)
+}
+/// Hello of the server: magic, chosen version or 0 if none, chosen parameter set or 0 if none and extensions.
+pub(crate) fn serverhello(version: u16, kem: u8, extensions: &[Extension]) -> Result<Vec<u8>, KyberauthError> {
+    let mut hello: Vec<u8> = Vec::new();
+    hello.extend_from_slice(&MAGIC);
+    hello.extend_from_slice(&version.to_be_bytes());
+    hello.push(kem);
+    hello.extend_from_slice(&encode(extensions)?);
+    Ok(hello)
+}
+/// Read the hello of the client and choose the first version of the client that is supported, and the parameter set
+/// `kem` of the key of the server if the client offers it. The hybrid exchange is only chosen if `hybrid` is true.
+pub(crate) async fn readclienthello<R>(socket: &mut R, kem: Kem, hybrid: bool) -> Result<ClientHello, KyberauthError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hello: Vec<u8> = vec![0; MAGIC.len() + 1];
+    socket.read_exact(&mut hello).await?;
+    if hello[..MAGIC.len()] != MAGIC {
+        return Err(KyberauthError::Handshake("Invalid magic bytes"));
+    }
+    let count = hello[MAGIC.len()] as usize;
+    let start = hello.len();
+    hello.resize(start + 2 * count + 1, 0);
+    socket.read_exact(&mut hello[start..]).await?;
+    let version = hello[start..start + 2 * count]
+        .chunks_exact(2)
+        .map(|version| u16::from_be_bytes([version[0], version[1]]))
+        .find(|version| VERSIONS.contains(version));
+    let count = hello[start + 2 * count] as usize;
+    let start = hello.len();
+    hello.resize(start + count, 0);
+    socket.read_exact(&mut hello[start..]).await?;
+    let kem = hello[start..]
+        .iter()
+        .find(|id| **id == kemid(kem, false) || (hybrid && **id == kemid(kem, true)))
+        .copied();
+    let extensions = readextensions(socket, &mut hello).await?;
+    Ok(ClientHello {
+        raw: hello,
+        version,
+        kem,
+        extensions,
+    })
+}
+/// Read the hello of the server, add both hellos to the transcript and fail if the server chose no supported version
+/// or parameter set.
+pub(crate) async fn readserverhello<R>(
+    socket: &mut R,
+    clienthello: &[u8],
+    kem: Kem,
+    hybrid: bool,
+    transcript: &mut Transcript,
+) -> Result<Negotiated, KyberauthError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hello: Vec<u8> = vec![0; MAGIC.len() + 2 + 1];
+    socket.read_exact(&mut hello).await?;
+    if hello[..MAGIC.len()] != MAGIC {
+        return Err(KyberauthError::Handshake("Invalid magic bytes"));
+    }
+    let version = u16::from_be_bytes([hello[MAGIC.len()], hello[MAGIC.len() + 1]]);
+    if !VERSIONS.contains(&version) {
+        return Err(KyberauthError::NoCommonVersion);
+    }
+    if hello[MAGIC.len() + 2] != kemid(kem, hybrid) {
+        return Err(KyberauthError::NoCommonKem);
+    }
+    let extensions = readextensions(socket, &mut hello).await?;
+    transcript.update(clienthello);
+    transcript.update(&hello);
+    Ok(Negotiated {
+        version,
+        kem,
+        hybrid,
+        extensions,
+    })
+}
+/// Length of the extensions then each extension as type, length and data
+fn encode(extensions: &[Extension]) -> Result<Vec<u8>, KyberauthError> {
+    let mut encoded: Vec<u8> = Vec::new();
+    let mut kinds: HashSet<u16> = HashSet::new();
+    for extension in extensions {
+        if !kinds.insert(extension.kind) {
+            return Err(KyberauthError::InvalidConfig("Duplicate extension"));
+        }
+        let length: u16 = extension
+            .data
+            .len()
+            .try_into()
+            .map_err(|_| KyberauthError::InvalidConfig("Extension too large"))?;
+        encoded.extend_from_slice(&extension.kind.to_be_bytes());
+        encoded.extend_from_slice(&length.to_be_bytes());
+        encoded.extend_from_slice(&extension.data);
+    }
+    let length: u16 = encoded
+        .len()
+        .try_into()
+        .map_err(|_| KyberauthError::InvalidConfig("Extensions too large"))?;
+    let mut message = length.to_be_bytes().to_vec();
+    message.extend_from_slice(&encoded);
+    Ok(message)
+}
+/// Read the extensions of a hello and append them to the raw hello
+async fn readextensions<R>(socket: &mut R, hello: &mut Vec<u8>) -> Result<Vec<Extension>, KyberauthError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length = [0u8; 2];
+    socket.read_exact(&mut length).await?;
+    hello.extend_from_slice(&length);
+    let mut encoded: Vec<u8> = vec![0; u16::from_be_bytes(length) as usize];
+    socket.read_exact(&mut encoded).await?;
+    hello.extend_from_slice(&encoded);
+    let mut extensions: Vec<Extension> = Vec::new();
+    let mut kinds: HashSet<u16> = HashSet::new();
+    let mut rest = &encoded[..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(KyberauthError::Handshake("Truncated extension"));
+        }
+        let kind = u16::from_be_bytes([rest[0], rest[1]]);
+        let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + length {
+            return Err(KyberauthError::Handshake("Truncated extension"));
+        }
+        if !kinds.insert(kind) {
+            return Err(KyberauthError::Handshake("Duplicate extension"));
+        }
+        extensions.push(Extension::new(kind, &rest[4..4 + length]));
+        rest = &rest[4 + length..];
+    }
+    Ok(extensions)
+}
@@ -0,0 +1,126 @@
+//! Hybrid key exchange: an X25519 exchange runs along the Kyber AKE and both shared secrets are combined, so the traffic
+//! keys stay secret as long as either Kyber or X25519 is unbroken.
+//!
+//! Each side has a static X25519 key, the classic half of its key, and sends it with a fresh ephemeral X25519 key right
+//! after its Kyber key. The key of the peer, checked against `authorized_keys` and `known_hosts` and returned by
+//! `getpeerkey`, is then its Kyber key followed by its static X25519 key. Three X25519 secrets are computed, ephemeral
+//! with ephemeral and each ephemeral key with the static key of the other side, and hashed with the Kyber secret and the
+//! transcript into the secret of the connection, like the X-Wing combiner.
+//!
+//! The hybrid mode is enabled with `ClientConfig::hybrid` and `ServerConfig::hybrid`. A client in the hybrid mode only
+//! offers it, so it fails with `NoCommonKem` against a server without it, while a server in the hybrid mode still
+//! accepts clients using Kyber alone. Early data and tickets are not available in the hybrid mode.
+use crate::error::KyberauthError;
+use crate::kdf::{Role, HASHSIZE};
+use crate::key::Kem;
+use rand::{CryptoRng, RngCore};
+use safe_pqc_kyber::KYBER_SSBYTES;
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+/// Size of an X25519 key
+pub const X25519BYTES: usize = 32;
+/// Size of the keys sent by each side after its Kyber key: its static then its ephemeral X25519 key
+pub(crate) const SHARESIZE: usize = 2 * X25519BYTES;
+const LABEL: &[u8] = b"kyberauth hybrid";
+//Prefix of the parameter set in the headers of hybrid key files
+pub(crate) const PREFIX: &str = "X25519-";
+/// Name written in the headers of hybrid key files
+pub(crate) fn getname(kem: Kem) -> String {
+    format!("{}{}", PREFIX, kem.getname())
+}
+/// Classic half of a hybrid key, an X25519 key pair.
+#[derive(Clone)]
+pub struct X25519Keypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+impl fmt::Debug for X25519Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "X25519Keypair({})", hex::encode(self.public))
+    }
+}
+impl X25519Keypair {
+    pub fn generate<R>(rng: &mut R) -> Self
+    where
+        R: RngCore + CryptoRng,
+    {
+        let secret = StaticSecret::random_from_rng(rng);
+        let public = PublicKey::from(&secret);
+        X25519Keypair { secret, public }
+    }
+    pub fn fromsecret(secret: [u8; X25519BYTES]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        X25519Keypair { secret, public }
+    }
+    pub fn getpublic(&self) -> [u8; X25519BYTES] {
+        self.public.to_bytes()
+    }
+    /// Get the secret key, to write it in a key file
+    pub(crate) fn getsecret(&self) -> [u8; X25519BYTES] {
+        self.secret.to_bytes()
+    }
+}
+/// X25519 keys of one side for one handshake: its static key and a fresh ephemeral key.
+pub(crate) struct Share {
+    keys: X25519Keypair,
+    ephemeral: StaticSecret,
+    message: [u8; SHARESIZE],
+}
+impl Share {
+    pub(crate) fn new(keys: &X25519Keypair) -> Self {
+        let ephemeral = StaticSecret::random_from_rng(rand::thread_rng());
+        let mut message = [0u8; SHARESIZE];
+        message[..X25519BYTES].copy_from_slice(keys.public.as_bytes());
+        message[X25519BYTES..].copy_from_slice(PublicKey::from(&ephemeral).as_bytes());
+        Share {
+            keys: keys.clone(),
+            ephemeral,
+            message,
+        }
+    }
+    /// Get the static then the ephemeral public key, as sent to the peer
+    pub(crate) fn getmessage(&self) -> &[u8; SHARESIZE] {
+        &self.message
+    }
+    /// Combine the Kyber secret with the X25519 secrets computed with the keys sent by the peer.
+    /// Fails if a key of the peer gives a secret without contribution, like a low order point.
+    pub(crate) fn combine(
+        &self,
+        role: Role,
+        peer: &[u8],
+        kyber: &[u8; KYBER_SSBYTES],
+        transcript: &[u8; HASHSIZE],
+    ) -> Result<[u8; KYBER_SSBYTES], KyberauthError> {
+        let peer: [u8; SHARESIZE] = peer
+            .try_into()
+            .map_err(|_| KyberauthError::Handshake("Invalid X25519 keys"))?;
+        let peerstatic = PublicKey::from(<[u8; X25519BYTES]>::try_from(&peer[..X25519BYTES]).unwrap());
+        let peerephemeral = PublicKey::from(<[u8; X25519BYTES]>::try_from(&peer[X25519BYTES..]).unwrap());
+        //Ephemeral with ephemeral, then client ephemeral with server static and client static with server ephemeral
+        let secrets = match role {
+            Role::Client => [
+                self.ephemeral.diffie_hellman(&peerephemeral),
+                self.ephemeral.diffie_hellman(&peerstatic),
+                self.keys.secret.diffie_hellman(&peerephemeral),
+            ],
+            Role::Server => [
+                self.ephemeral.diffie_hellman(&peerephemeral),
+                self.keys.secret.diffie_hellman(&peerephemeral),
+                self.ephemeral.diffie_hellman(&peerstatic),
+            ],
+        };
+        if secrets.iter().any(|secret| !secret.was_contributory()) {
+            return Err(KyberauthError::Handshake("Invalid X25519 keys"));
+        }
+        let mut hasher = Sha3_256::new();
+        hasher.update(LABEL);
+        hasher.update(kyber);
+        for secret in &secrets {
+            hasher.update(secret.as_bytes());
+        }
+        hasher.update(transcript);
+        Ok(hasher.finalize().into())
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::KyberauthError;
+use hkdf::Hkdf;
+use safe_pqc_kyber::KYBER_SSBYTES;
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+pub(crate) const HASHSIZE: usize = 256 / 8;
+pub(crate) const IVSIZE: usize = 96 / 8;
+//First byte sent by the client, telling the server which handshake follows
+pub(crate) const MODEFULL: u8 = 0;
+pub(crate) const MODERESUME: u8 = 1;
+pub(crate) const MODEEARLY: u8 = 2;
+//Answer of the server to a ticket or to early data
+pub(crate) const ACCEPTED: u8 = 1;
+pub(crate) const REJECTED: u8 = 0;
+/// Size of the random value sent by each side when resuming a session.
+pub(crate) const RANDOMSIZE: usize = 32;
+/// Side of the connection, each side sends with its own keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Client,
+    Server,
+}
+/// Running hash of every handshake message, binding the traffic keys to the whole handshake.
+#[derive(Debug, Clone)]
+pub(crate) struct Transcript(Sha3_256);
+impl Transcript {
+    pub(crate) fn new() -> Self {
+        Transcript(Sha3_256::new())
+    }
+    /// Add a message to the transcript. Messages are length prefixed so that they cannot be shifted.
+    pub(crate) fn update<T>(&mut self, message: T)
+    where
+        T: AsRef<[u8]>,
+    {
+        let message = message.as_ref();
+        self.0.update((message.len() as u32).to_be_bytes());
+        self.0.update(message);
+    }
+    pub(crate) fn hash(&self) -> [u8; HASHSIZE] {
+        self.0.clone().finalize().into()
+    }
+}
+/// Keys and nonce bases of both directions of a connection.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub(crate) struct TrafficSecrets {
+    pub(crate) clientkey: [u8; KYBER_SSBYTES],
+    pub(crate) clientiv: [u8; IVSIZE],
+    pub(crate) serverkey: [u8; KYBER_SSBYTES],
+    pub(crate) serveriv: [u8; IVSIZE],
+    pub(crate) exporter: [u8; HASHSIZE],
+}
+impl TrafficSecrets {
+    /// Derive the traffic secrets from the AKE shared secret, with the transcript hash as salt.
+    pub(crate) fn new(secret: &[u8; KYBER_SSBYTES], transcript: &[u8; HASHSIZE]) -> Self {
+        let hkdf = Hkdf::<Sha3_256>::new(Some(transcript), secret);
+        let mut secrets = TrafficSecrets {
+            clientkey: [0; KYBER_SSBYTES],
+            clientiv: [0; IVSIZE],
+            serverkey: [0; KYBER_SSBYTES],
+            serveriv: [0; IVSIZE],
+            exporter: [0; HASHSIZE],
+        };
+        //Output lengths are far below the HKDF limit, expand cannot fail
+        hkdf.expand(b"kyberauth client key", &mut secrets.clientkey).unwrap();
+        hkdf.expand(b"kyberauth client iv", &mut secrets.clientiv).unwrap();
+        hkdf.expand(b"kyberauth server key", &mut secrets.serverkey).unwrap();
+        hkdf.expand(b"kyberauth server iv", &mut secrets.serveriv).unwrap();
+        hkdf.expand(b"kyberauth exporter", &mut secrets.exporter).unwrap();
+        secrets
+    }
+}
+/// Secret from which keying material is exported, independent from the traffic keys.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct Exporter(pub(crate) [u8; HASHSIZE]);
+impl fmt::Debug for Exporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Exporter")
+    }
+}
+impl Exporter {
+    /// Expand the exporter secret with the label and the context. Both are length prefixed so that
+    /// different pairs never give the same output. At most 8160 bytes can be exported.
+    pub(crate) fn export(&self, label: &[u8], context: &[u8], len: usize) -> Result<Vec<u8>, KyberauthError> {
+        //The secret has the size of the hash, it is a valid pseudorandom key
+        let hkdf = Hkdf::<Sha3_256>::from_prk(&self.0).unwrap();
+        let mut info: Vec<u8> = Vec::with_capacity(8 + label.len() + context.len());
+        info.extend_from_slice(&(label.len() as u32).to_be_bytes());
+        info.extend_from_slice(label);
+        info.extend_from_slice(&(context.len() as u32).to_be_bytes());
+        info.extend_from_slice(context);
+        let mut output = vec![0u8; len];
+        if hkdf.expand(&info, &mut output).is_err() {
+            return Err(KyberauthError::ExportTooLong);
+        }
+        Ok(output)
+    }
+}
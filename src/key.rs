@@ -1,16 +1,227 @@
+//! Key encapsulation mechanisms of the handshake. Round 3 Kyber comes from `safe_pqc_kyber`, built with a single
+//! parameter set chosen by its cargo features. ML-KEM of FIPS 203 comes from `aws-lc-rs`, and its parameter set is
+//! chosen at runtime by the keys, see [`KemKeypair`].
+//!
+//! The client offers the parameter set of its key in its hello and the server accepts it if its own key has the same
+//! one, otherwise both fail with `NoCommonKem`. Each connection uses the parameter set of its keys, so a program can
+//! hold keys of several parameter sets.
+use crate::error::KyberauthError;
+use aws_lc_rs::kem::{Algorithm, DecapsulationKey, EncapsulationKey, ML_KEM_1024, ML_KEM_512, ML_KEM_768};
 use safe_pqc_kyber::*;
-use zeroize::Zeroize;
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+const AKELABEL: &[u8] = b"kyberauth ml-kem ake";
+/// Parameter set of the keys, written in key files and negotiated in the hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Kem {
+    Kyber512,
+    Kyber768,
+    Kyber1024,
+    MlKem512,
+    MlKem768,
+    MlKem1024,
+}
+impl Kem {
+    /// Kyber parameter set this crate is built with, the only Kyber one it can use
+    pub const CURRENT: Kem = match KYBER_K {
+        2 => Kem::Kyber512,
+        3 => Kem::Kyber768,
+        4 => Kem::Kyber1024,
+        _ => panic!("Unknown Kyber parameter set"),
+    };
+    /// Parameter sets this build can use
+    pub const SUPPORTED: [Kem; 4] = [Kem::CURRENT, Kem::MlKem512, Kem::MlKem768, Kem::MlKem1024];
+    pub fn issupported(&self) -> bool {
+        Kem::SUPPORTED.contains(self)
+    }
+    /// Whether this is ML-KEM of FIPS 203 rather than round 3 Kyber
+    pub fn ismlkem(&self) -> bool {
+        matches!(self, Kem::MlKem512 | Kem::MlKem768 | Kem::MlKem1024)
+    }
+    /// Get the id sent in the hello
+    pub fn getid(&self) -> u8 {
+        match self {
+            Kem::Kyber512 => 1,
+            Kem::Kyber768 => 2,
+            Kem::Kyber1024 => 3,
+            Kem::MlKem512 => 4,
+            Kem::MlKem768 => 5,
+            Kem::MlKem1024 => 6,
+        }
+    }
+    pub fn fromid(id: u8) -> Option<Kem> {
+        match id {
+            1 => Some(Kem::Kyber512),
+            2 => Some(Kem::Kyber768),
+            3 => Some(Kem::Kyber1024),
+            4 => Some(Kem::MlKem512),
+            5 => Some(Kem::MlKem768),
+            6 => Some(Kem::MlKem1024),
+            _ => None,
+        }
+    }
+    /// Get the name written in the headers of key files
+    pub fn getname(&self) -> &'static str {
+        match self {
+            Kem::Kyber512 => "KYBER512",
+            Kem::Kyber768 => "KYBER768",
+            Kem::Kyber1024 => "KYBER1024",
+            Kem::MlKem512 => "ML-KEM-512",
+            Kem::MlKem768 => "ML-KEM-768",
+            Kem::MlKem1024 => "ML-KEM-1024",
+        }
+    }
+    pub fn fromname(name: &str) -> Option<Kem> {
+        [Kem::Kyber512, Kem::Kyber768, Kem::Kyber1024, Kem::MlKem512, Kem::MlKem768, Kem::MlKem1024]
+            .into_iter()
+            .find(|kem| kem.getname() == name)
+    }
+    pub fn getpublicsize(&self) -> usize {
+        match self {
+            Kem::Kyber512 | Kem::MlKem512 => 800,
+            Kem::Kyber768 | Kem::MlKem768 => 1184,
+            Kem::Kyber1024 | Kem::MlKem1024 => 1568,
+        }
+    }
+    pub fn getsecretsize(&self) -> usize {
+        match self {
+            Kem::Kyber512 | Kem::MlKem512 => 1632,
+            Kem::Kyber768 | Kem::MlKem768 => 2400,
+            Kem::Kyber1024 | Kem::MlKem1024 => 3168,
+        }
+    }
+    pub fn getciphertextsize(&self) -> usize {
+        match self {
+            Kem::Kyber512 | Kem::MlKem512 => 768,
+            Kem::Kyber768 | Kem::MlKem768 => 1088,
+            Kem::Kyber1024 | Kem::MlKem1024 => 1568,
+        }
+    }
+    /// Size of the first message of the key exchange, sent by the client
+    pub(crate) fn getinitsize(&self) -> usize {
+        self.getpublicsize() + self.getciphertextsize()
+    }
+    /// Size of the answer of the server to the first message of the key exchange
+    pub(crate) fn getresponsesize(&self) -> usize {
+        2 * self.getciphertextsize()
+    }
+    fn algorithm(&self) -> Option<&'static Algorithm> {
+        match self {
+            Kem::MlKem512 => Some(&ML_KEM_512),
+            Kem::MlKem768 => Some(&ML_KEM_768),
+            Kem::MlKem1024 => Some(&ML_KEM_1024),
+            _ => None,
+        }
+    }
+    /// Fail if the parameter set cannot be used by this build
+    fn check(&self) -> Result<(), KyberauthError> {
+        match self.issupported() {
+            true => Ok(()),
+            false => Err(KyberauthError::InvalidConfig("Kyber parameter set not built")),
+        }
+    }
+}
+/// Keys used by the handshake: a `safe_pqc_kyber::Keypair` of the parameter set of the build, or a [`KemKeypair`].
+pub trait KemKeys: Send + Sync {
+    fn getkem(&self) -> Kem;
+    fn getpublic(&self) -> &[u8];
+    /// Get the secret key, to write it in a key file
+    fn getsecret(&self) -> &[u8];
+}
+impl KemKeys for Keypair {
+    fn getkem(&self) -> Kem {
+        Kem::CURRENT
+    }
+    fn getpublic(&self) -> &[u8] {
+        &self.public
+    }
+    fn getsecret(&self) -> &[u8] {
+        &self.secret
+    }
+}
+/// Key pair of any parameter set this build can use, ML-KEM ones included.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct KemKeypair {
+    #[zeroize(skip)]
+    kem: Kem,
+    #[zeroize(skip)]
+    public: Vec<u8>,
+    secret: Vec<u8>,
+}
+impl fmt::Debug for KemKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KemKeypair({}, {})", self.kem.getname(), hex::encode(&self.public))
+    }
+}
+impl KemKeypair {
+    /// Generate a key pair. Fails with `InvalidConfig` for a Kyber parameter set this crate is not built with.
+    pub fn generate(kem: Kem) -> Result<Self, KyberauthError> {
+        kem.check()?;
+        let Some(algorithm) = kem.algorithm() else {
+            return Ok(KemKeypair::from(&keypair(&mut rand::thread_rng())));
+        };
+        let secret = DecapsulationKey::generate(algorithm).map_err(rejected)?;
+        let public = secret.encapsulation_key().and_then(|public| public.key_bytes()).map_err(rejected)?;
+        let secret = secret.key_bytes().map_err(rejected)?;
+        Ok(KemKeypair {
+            kem,
+            public: public.as_ref().to_vec(),
+            secret: secret.as_ref().to_vec(),
+        })
+    }
+    /// Build a key pair from its keys, fails with `KeyMismatch` if they do not belong together.
+    pub fn fromkeys(kem: Kem, public: &[u8], secret: &[u8]) -> Result<Self, KyberauthError> {
+        kem.check()?;
+        if public.len() != kem.getpublicsize() || secret.len() != kem.getsecretsize() {
+            return Err(KyberauthError::InvalidKeyFile("Invalid key length"));
+        }
+        //A secret encapsulated to the public key must come back with the secret key
+        let (ciphertext, expected) = encapsulate(kem, public)?;
+        let expected = Zeroizing::new(expected);
+        let shared = Zeroizing::new(decapsulate(kem, &ciphertext, secret)?);
+        if expected != shared {
+            return Err(KyberauthError::KeyMismatch);
+        }
+        Ok(KemKeypair {
+            kem,
+            public: public.to_vec(),
+            secret: secret.to_vec(),
+        })
+    }
+}
+impl From<&Keypair> for KemKeypair {
+    fn from(keys: &Keypair) -> Self {
+        KemKeypair {
+            kem: Kem::CURRENT,
+            public: keys.public.to_vec(),
+            secret: keys.secret.to_vec(),
+        }
+    }
+}
+impl KemKeys for KemKeypair {
+    fn getkem(&self) -> Kem {
+        self.kem
+    }
+    fn getpublic(&self) -> &[u8] {
+        &self.public
+    }
+    fn getsecret(&self) -> &[u8] {
+        &self.secret
+    }
+}
 pub fn keypairfrom<R>(
     public: &mut [u8; KYBER_PUBLICKEYBYTES],
     secret: &mut [u8; KYBER_SECRETKEYBYTES],
     rng: &mut R,
-) -> Result<Keypair, KyberError>
+) -> Result<Keypair, KyberauthError>
 where
     R: RngCore + CryptoRng,
 {
     //Try to encapsulate and decapsule to verify secret key matches public key
-    let (ciphertext, shared_secret) = encapsulate(public, rng)?;
-    let expected_shared_secret = decapsulate(&ciphertext, secret)?;
+    let (ciphertext, shared_secret) = safe_pqc_kyber::encapsulate(public, rng)?;
+    let expected_shared_secret = safe_pqc_kyber::decapsulate(&ciphertext, secret)?;
     //If it does match, return a KeyPair
     if expected_shared_secret == shared_secret {
         let mut public2 = *public;
@@ -26,6 +237,133 @@ where
         Ok(key)
     } else {
         //Else return an error
-        Err(KyberError::InvalidInput)
+        Err(KyberauthError::KeyMismatch)
+    }
+}
+/// Encapsulate a fresh secret to the public key, returns the ciphertext and the secret.
+pub(crate) fn encapsulate(kem: Kem, public: &[u8]) -> Result<(Vec<u8>, [u8; KYBER_SSBYTES]), KyberauthError> {
+    kem.check()?;
+    let Some(algorithm) = kem.algorithm() else {
+        let (ciphertext, secret) = safe_pqc_kyber::encapsulate(public, &mut rand::thread_rng())?;
+        return Ok((ciphertext.to_vec(), secret));
+    };
+    let public = EncapsulationKey::new(algorithm, public).map_err(rejected)?;
+    let (ciphertext, secret) = public.encapsulate().map_err(rejected)?;
+    Ok((ciphertext.as_ref().to_vec(), tosecret(secret.as_ref())?))
+}
+/// Get back the secret encapsulated in the ciphertext with the secret key.
+pub(crate) fn decapsulate(kem: Kem, ciphertext: &[u8], secret: &[u8]) -> Result<[u8; KYBER_SSBYTES], KyberauthError> {
+    kem.check()?;
+    let Some(algorithm) = kem.algorithm() else {
+        return Ok(safe_pqc_kyber::decapsulate(ciphertext, secret)?);
+    };
+    let secret = DecapsulationKey::new(algorithm, secret).map_err(rejected)?;
+    mldecapsulate(kem, &secret, ciphertext).map_err(KyberauthError::Kyber)
+}
+fn mldecapsulate(kem: Kem, secret: &DecapsulationKey, ciphertext: &[u8]) -> Result<[u8; KYBER_SSBYTES], KyberError> {
+    if ciphertext.len() != kem.getciphertextsize() {
+        return Err(KyberError::InvalidInput);
     }
+    let shared = secret.decapsulate(ciphertext.into()).map_err(|_| KyberError::Decapsulation)?;
+    tosecret(shared.as_ref()).map_err(|_| KyberError::Decapsulation)
+}
+fn tosecret(shared: &[u8]) -> Result<[u8; KYBER_SSBYTES], KyberauthError> {
+    shared.try_into().map_err(rejected)
+}
+//Errors of aws-lc-rs carry no detail
+fn rejected<E>(_: E) -> KyberauthError {
+    KyberauthError::Kyber(KyberError::InvalidInput)
+}
+fn invalid<E>(_: E) -> KyberauthError {
+    KyberauthError::KeyExchange(KyberError::InvalidInput)
+}
+/// Client side of the authenticated key exchange, between its first message and the answer of the server.
+///
+/// With Kyber it is the AKE of `safe_pqc_kyber`. With ML-KEM it follows the same construction: the client sends a fresh
+/// ephemeral key and a secret encapsulated to the key of the server, the server answers with secrets encapsulated to the
+/// ephemeral key and to the key of the client, and the three secrets are hashed together.
+pub(crate) enum AkeClient {
+    Kyber(Box<Ake>),
+    MlKem {
+        ephemeral: DecapsulationKey,
+        server: Zeroizing<[u8; KYBER_SSBYTES]>,
+    },
+}
+impl AkeClient {
+    /// Start the exchange with the public key of the server, returns the state and the first message.
+    pub(crate) fn init(kem: Kem, serverkey: &[u8]) -> Result<(Self, Vec<u8>), KyberauthError> {
+        kem.check()?;
+        let Some(algorithm) = kem.algorithm() else {
+            let serverkey: &[u8; KYBER_PUBLICKEYBYTES] = serverkey.try_into().map_err(invalid)?;
+            let mut alice = Box::new(Ake::new());
+            let init = alice.client_init(serverkey, &mut rand::thread_rng());
+            return Ok((AkeClient::Kyber(alice), init.to_vec()));
+        };
+        let ephemeral = DecapsulationKey::generate(algorithm).map_err(invalid)?;
+        let public = ephemeral.encapsulation_key().and_then(|public| public.key_bytes()).map_err(invalid)?;
+        let (ciphertext, server) = encapsulate(kem, serverkey).map_err(invalid)?;
+        let mut init = public.as_ref().to_vec();
+        init.extend_from_slice(&ciphertext);
+        let server = Zeroizing::new(server);
+        Ok((AkeClient::MlKem { ephemeral, server }, init))
+    }
+    /// Finish the exchange with the answer of the server and the secret key of the client, returns the shared secret.
+    pub(crate) fn confirm(self, kem: Kem, answer: &[u8], secret: &[u8]) -> Result<[u8; KYBER_SSBYTES], KyberauthError> {
+        match self {
+            AkeClient::Kyber(mut alice) => {
+                let answer: [u8; AKE_RESPONSE_BYTES] = answer.try_into().map_err(invalid)?;
+                let secret: &[u8; KYBER_SECRETKEYBYTES] = secret.try_into().map_err(invalid)?;
+                alice.client_confirm(answer, secret).map_err(KyberauthError::KeyExchange)?;
+                Ok(alice.shared_secret)
+            }
+            AkeClient::MlKem { ephemeral, server } => {
+                if answer.len() != kem.getresponsesize() {
+                    return Err(KyberauthError::KeyExchange(KyberError::InvalidInput));
+                }
+                let (forephemeral, forclient) = answer.split_at(kem.getciphertextsize());
+                let fromephemeral = Zeroizing::new(mldecapsulate(kem, &ephemeral, forephemeral).map_err(KyberauthError::KeyExchange)?);
+                let fromclient = Zeroizing::new(decapsulate(kem, forclient, secret).map_err(invalid)?);
+                Ok(combine(&server[..], &fromephemeral[..], &fromclient[..]))
+            }
+        }
+    }
+}
+/// Server side of the authenticated key exchange, returns the answer to the client and the shared secret.
+pub(crate) fn akeserver(
+    kem: Kem,
+    init: &[u8],
+    clientkey: &[u8],
+    secret: &[u8],
+) -> Result<(Vec<u8>, [u8; KYBER_SSBYTES]), KyberauthError> {
+    kem.check()?;
+    if init.len() != kem.getinitsize() || clientkey.len() != kem.getpublicsize() {
+        return Err(KyberauthError::KeyExchange(KyberError::InvalidInput));
+    }
+    if kem.algorithm().is_none() {
+        let init: [u8; AKE_INIT_BYTES] = init.try_into().map_err(invalid)?;
+        let clientkey: &[u8; KYBER_PUBLICKEYBYTES] = clientkey.try_into().map_err(invalid)?;
+        let secret: &[u8; KYBER_SECRETKEYBYTES] = secret.try_into().map_err(invalid)?;
+        let mut bob = Ake::new();
+        let answer = bob
+            .server_receive(init, clientkey, secret, &mut rand::thread_rng())
+            .map_err(KyberauthError::KeyExchange)?;
+        return Ok((answer.to_vec(), bob.shared_secret));
+    }
+    let (ephemeral, forserver) = init.split_at(kem.getpublicsize());
+    let fromserver = Zeroizing::new(decapsulate(kem, forserver, secret).map_err(invalid)?);
+    let (mut answer, toephemeral) = encapsulate(kem, ephemeral).map_err(invalid)?;
+    let toephemeral = Zeroizing::new(toephemeral);
+    let (forclient, toclient) = encapsulate(kem, clientkey).map_err(invalid)?;
+    let toclient = Zeroizing::new(toclient);
+    answer.extend_from_slice(&forclient);
+    Ok((answer, combine(&fromserver[..], &toephemeral[..], &toclient[..])))
+}
+/// Hash the secrets encapsulated to the key of the server, the ephemeral key and the key of the client
+fn combine(server: &[u8], ephemeral: &[u8], client: &[u8]) -> [u8; KYBER_SSBYTES] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(AKELABEL);
+    hasher.update(server);
+    hasher.update(ephemeral);
+    hasher.update(client);
+    hasher.finalize().into()
 }
@@ -0,0 +1,123 @@
+//! Typed messages, serialized with serde before being encrypted. Each encoding has its own cargo feature:
+//! `bincode`, `postcard` and `json`.
+use crate::aes::Connection;
+use crate::error::KyberauthError;
+use crate::split::{EncryptedReader, EncryptedWriter};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+/// Serialization format of a typed message. Both sides must use the same encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[cfg(feature = "bincode")]
+    Bincode,
+    #[cfg(feature = "postcard")]
+    Postcard,
+    #[cfg(feature = "json")]
+    Json,
+}
+/// Error of a typed message. Decoding errors are reported apart from decryption and socket errors.
+#[derive(Debug)]
+pub enum MessageError {
+    /// The message could not be sent, received or decrypted.
+    Connection(KyberauthError),
+    /// The value could not be serialized.
+    Encode(Box<dyn Error + Send + Sync>),
+    /// The message was decrypted but is not a valid value of the expected type.
+    Decode(Box<dyn Error + Send + Sync>),
+}
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Connection(e) => write!(f, "Message not transmitted: {}", e),
+            MessageError::Encode(e) => write!(f, "Message not encoded: {}", e),
+            MessageError::Decode(e) => write!(f, "Message not decoded: {}", e),
+        }
+    }
+}
+impl Error for MessageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MessageError::Connection(e) => Some(e),
+            MessageError::Encode(e) | MessageError::Decode(e) => Some(e.as_ref()),
+        }
+    }
+}
+impl From<KyberauthError> for MessageError {
+    fn from(e: KyberauthError) -> Self {
+        MessageError::Connection(e)
+    }
+}
+impl Encoding {
+    /// Serialize a value with this encoding
+    pub fn encode<T>(&self, value: &T) -> Result<Vec<u8>, MessageError>
+    where
+        T: Serialize + ?Sized,
+    {
+        match *self {
+            #[cfg(feature = "bincode")]
+            Encoding::Bincode => bincode::serialize(value).map_err(|e| MessageError::Encode(e)),
+            #[cfg(feature = "postcard")]
+            Encoding::Postcard => postcard::to_allocvec(value).map_err(|e| MessageError::Encode(Box::new(e))),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| MessageError::Encode(Box::new(e))),
+        }
+    }
+    /// Deserialize a value with this encoding
+    pub fn decode<T>(&self, data: &[u8]) -> Result<T, MessageError>
+    where
+        T: DeserializeOwned,
+    {
+        match *self {
+            #[cfg(feature = "bincode")]
+            Encoding::Bincode => bincode::deserialize(data).map_err(|e| MessageError::Decode(e)),
+            #[cfg(feature = "postcard")]
+            Encoding::Postcard => postcard::from_bytes(data).map_err(|e| MessageError::Decode(Box::new(e))),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| MessageError::Decode(Box::new(e))),
+        }
+    }
+}
+impl Connection {
+    /// Serialize a value and send it as one message
+    pub async fn send_message<T>(&mut self, value: &T, encoding: Encoding) -> Result<(), MessageError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let data = encoding.encode(value)?;
+        Ok(self.senddata(data).await?)
+    }
+    /// Receive one message and deserialize it. Returns `None` once the peer closed the connection.
+    pub async fn recv_message<T>(&mut self, encoding: Encoding) -> Result<Option<T>, MessageError>
+    where
+        T: DeserializeOwned,
+    {
+        match self.receivedata().await? {
+            Some(data) => Ok(Some(encoding.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
+impl EncryptedWriter {
+    /// Serialize a value and send it as one message
+    pub async fn send_message<T>(&mut self, value: &T, encoding: Encoding) -> Result<(), MessageError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let data = encoding.encode(value)?;
+        Ok(self.senddata(data).await?)
+    }
+}
+impl EncryptedReader {
+    /// Receive one message and deserialize it. Returns `None` once the peer closed the connection.
+    pub async fn recv_message<T>(&mut self, encoding: Encoding) -> Result<Option<T>, MessageError>
+    where
+        T: DeserializeOwned,
+    {
+        match self.receivedata().await? {
+            Some(data) => Ok(Some(encoding.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Logical streams sharing one connection. Each stream has an identifier, its own lifecycle and a credit
+//! based flow control, so a slow reader of a stream does not block the other streams.
+//!
+//! Every frame is sent as one message of the connection: `[type u8][stream id u32 BE][payload]`.
+//! Streams opened by the client have odd identifiers, streams opened by the server even ones.
+//!
+//! A peer giving more credit than [`MAXWINDOW`] to a stream or opening more streams than allowed by the
+//! [`SessionConfig`] gets the stream reset.
+use crate::aes::Connection;
+use crate::error::KyberauthError;
+use crate::kdf::Role;
+use crate::split::{EncryptedReader, EncryptedWriter};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Semaphore};
+/// Bytes a stream can receive before the reader consumes them, unless set with [`SessionConfig::window`].
+pub const DEFAULTWINDOW: u32 = 256 * 1024;
+/// Largest window of a stream, and largest credit the peer can give to a stream.
+pub const MAXWINDOW: u32 = 1 << 28;
+/// Streams the peer can have open at the same time, unless set with [`SessionConfig::maxstreams`].
+pub const DEFAULTMAXSTREAMS: usize = 256;
+/// Maximum data carried by a frame, bigger writes are split.
+const MAXFRAME: usize = 16 * 1024;
+const FRAMEHEADER: usize = 5;
+//Data of a stream
+const DATA: u8 = 0;
+//Credit given to the peer, payload is the number of bytes as u32
+const WINDOW: u8 = 1;
+//New stream, the opener can receive up to the window given in payload
+const OPEN: u8 = 2;
+//The sender will not send data anymore on the stream
+const CLOSE: u8 = 3;
+//The stream is aborted in both directions
+const RESET: u8 = 4;
+type Inbound = mpsc::UnboundedSender<Result<Vec<u8>, KyberauthError>>;
+type Streams = Arc<Mutex<HashMap<u32, Entry>>>;
+/// State of a stream shared with the task reading the connection.
+#[derive(Debug)]
+struct Entry {
+    //None once the peer closed or reset the stream
+    inbound: Option<Inbound>,
+    //Credit to send data, one permit is one byte
+    credit: Arc<Semaphore>,
+    //Credit the peer still has to send data
+    remaining: u64,
+}
+#[derive(Debug)]
+enum Command {
+    Frame(Vec<u8>),
+    Close,
+}
+/// Options of a session
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    window: u32,
+    maxstreams: usize,
+}
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            window: DEFAULTWINDOW,
+            maxstreams: DEFAULTMAXSTREAMS,
+        }
+    }
+}
+impl SessionConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the bytes each stream can receive before they are read, between 1 and [`MAXWINDOW`].
+    pub fn window(mut self, window: u32) -> Self {
+        self.window = window.clamp(1, MAXWINDOW);
+        self
+    }
+    pub fn getwindow(&self) -> u32 {
+        self.window
+    }
+    /// Set the streams the peer can have open at the same time, the next ones are reset.
+    pub fn maxstreams(mut self, maxstreams: usize) -> Self {
+        self.maxstreams = maxstreams;
+        self
+    }
+    pub fn getmaxstreams(&self) -> usize {
+        self.maxstreams
+    }
+}
+/// Connection shared by several streams. Frames are written and read by tasks spawned on the tokio runtime.
+#[derive(Debug)]
+pub struct Session {
+    commands: mpsc::UnboundedSender<Command>,
+    incoming: mpsc::UnboundedReceiver<MuxStream>,
+    streams: Streams,
+    nextid: u32,
+    window: u32,
+}
+/// Bidirectional stream of a session. Dropping it resets the stream unless both sides closed it.
+#[derive(Debug)]
+pub struct MuxStream {
+    id: u32,
+    commands: mpsc::UnboundedSender<Command>,
+    inbound: mpsc::UnboundedReceiver<Result<Vec<u8>, KyberauthError>>,
+    credit: Arc<Semaphore>,
+    streams: Streams,
+    window: u32,
+    //Bytes read but not yet given back as credit to the peer
+    consumed: u32,
+    closed: bool,
+    finished: bool,
+}
+impl Session {
+    /// Share the connection between streams, with the default configuration.
+    pub fn new(connection: Connection) -> Self {
+        Session::with_config(connection, &SessionConfig::default())
+    }
+    /// Share the connection between streams, each stream can receive `window` bytes before they are read.
+    pub fn with_window(connection: Connection, window: u32) -> Self {
+        Session::with_config(connection, &SessionConfig::new().window(window))
+    }
+    /// Share the connection between streams with the given configuration.
+    pub fn with_config(connection: Connection, config: &SessionConfig) -> Self {
+        let window = config.window;
+        let role = connection.role;
+        let (reader, writer) = connection.into_split();
+        let (commands, queue) = mpsc::unbounded_channel();
+        let (accept, incoming) = mpsc::unbounded_channel();
+        let streams: Streams = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(writeframes(writer, queue));
+        tokio::spawn(readframes(reader, role, commands.downgrade(), accept, streams.clone(), config.clone()));
+        Session {
+            commands,
+            incoming,
+            streams,
+            nextid: match role {
+                Role::Client => 1,
+                Role::Server => 2,
+            },
+            window,
+        }
+    }
+    /// Open a new stream. The peer gets it from `accept`, data can be sent once it gave some credit.
+    /// Fails with `StreamIdsExhausted` once every identifier was used, a new session is then needed.
+    pub fn open(&mut self) -> Result<MuxStream, KyberauthError> {
+        let id = self.nextid;
+        self.nextid = id.checked_add(2).ok_or(KyberauthError::StreamIdsExhausted)?;
+        let stream = MuxStream::new(id, self.commands.clone(), self.streams.clone(), self.window, 0);
+        send(&self.commands, frame(OPEN, id, &self.window.to_be_bytes()))?;
+        Ok(stream)
+    }
+    /// Wait for a stream opened by the peer. Returns `None` once the connection is closed.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+    /// Close the connection once the frames already queued are sent. Open streams end with an error.
+    pub fn close(&self) {
+        let _ = self.commands.send(Command::Close);
+    }
+}
+impl MuxStream {
+    fn new(id: u32, commands: mpsc::UnboundedSender<Command>, streams: Streams, window: u32, credit: u32) -> Self {
+        let (inbound, receiver) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(credit as usize));
+        streams.lock().unwrap().insert(
+            id,
+            Entry {
+                inbound: Some(inbound),
+                credit: credit.clone(),
+                remaining: window as u64,
+            },
+        );
+        MuxStream {
+            id,
+            commands,
+            inbound: receiver,
+            credit,
+            streams,
+            window,
+            consumed: 0,
+            closed: false,
+            finished: false,
+        }
+    }
+    /// Get the identifier of the stream, the same on both sides
+    pub fn getid(&self) -> u32 {
+        self.id
+    }
+    /// Send data on the stream, waiting for credit from the peer if needed.
+    pub async fn senddata<T>(&mut self, text: T) -> Result<(), KyberauthError> where T: AsRef<[u8]> {
+        if self.closed {
+            return Err(KyberauthError::StreamClosed);
+        }
+        let mut text = text.as_ref();
+        while !text.is_empty() {
+            let size = self.takecredit(text.len().min(MAXFRAME)).await?;
+            send(&self.commands, frame(DATA, self.id, &text[..size]))?;
+            text = &text[size..];
+        }
+        Ok(())
+    }
+    /// Receive the next data of the stream. Data is not split as it was sent, it is a stream of bytes.
+    /// Returns `None` once the peer closed its side of the stream.
+    pub async fn receivedata(&mut self) -> Result<Option<Vec<u8>>, KyberauthError> {
+        if self.finished {
+            return Ok(None);
+        }
+        match self.inbound.recv().await {
+            Some(Ok(data)) => {
+                self.givecredit(data.len() as u32)?;
+                Ok(Some(data))
+            }
+            Some(Err(e)) => {
+                self.finished = true;
+                Err(e)
+            }
+            None => {
+                self.finished = true;
+                Ok(None)
+            }
+        }
+    }
+    /// Close the sending side of the stream, data can still be received.
+    pub fn close(&mut self) -> Result<(), KyberauthError> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        send(&self.commands, frame(CLOSE, self.id, &[]))
+    }
+    /// Wait for at least one byte of credit and take up to `size` bytes.
+    async fn takecredit(&self, size: usize) -> Result<usize, KyberauthError> {
+        let reset = || KyberauthError::StreamReset("Stream reset");
+        self.credit.acquire().await.map_err(|_| reset())?.forget();
+        let extra = self.credit.available_permits().min(size - 1);
+        if extra > 0 {
+            self.credit.try_acquire_many(extra as u32).map_err(|_| reset())?.forget();
+        }
+        Ok(1 + extra)
+    }
+    /// Give credit back to the peer once half of the window was read.
+    fn givecredit(&mut self, size: u32) -> Result<(), KyberauthError> {
+        self.consumed += size;
+        if self.consumed < self.window / 2 {
+            return Ok(());
+        }
+        let consumed = self.consumed;
+        self.consumed = 0;
+        if let Some(entry) = self.streams.lock().unwrap().get_mut(&self.id) {
+            entry.remaining += consumed as u64;
+        }
+        send(&self.commands, frame(WINDOW, self.id, &consumed.to_be_bytes()))
+    }
+}
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.id);
+        if !(self.closed && self.finished) {
+            let _ = self.commands.send(Command::Frame(frame(RESET, self.id, &[])));
+        }
+    }
+}
+fn frame(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::with_capacity(FRAMEHEADER + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+fn send(commands: &mpsc::UnboundedSender<Command>, frame: Vec<u8>) -> Result<(), KyberauthError> {
+    commands.send(Command::Frame(frame)).map_err(|_| KyberauthError::Closed)
+}
+/// Write queued frames to the connection until the session is closed or the session and every stream are dropped.
+async fn writeframes(mut writer: EncryptedWriter, mut queue: mpsc::UnboundedReceiver<Command>) {
+    while let Some(Command::Frame(frame)) = queue.recv().await {
+        if writer.senddata(frame).await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.clean().await;
+}
+/// Read frames from the connection and dispatch them to the streams.
+async fn readframes(
+    mut reader: EncryptedReader,
+    role: Role,
+    commands: mpsc::WeakUnboundedSender<Command>,
+    accept: mpsc::UnboundedSender<MuxStream>,
+    streams: Streams,
+    config: SessionConfig,
+) {
+    let error = loop {
+        let message = match reader.receivedata().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break KyberauthError::Truncated("Session closed"),
+            Err(e) => break e,
+        };
+        if message.len() < FRAMEHEADER {
+            break KyberauthError::InvalidRecord("Frame too short");
+        }
+        //Nothing can be sent once the session and every stream are dropped
+        let Some(commands) = commands.upgrade() else {
+            continue;
+        };
+        let kind = message[0];
+        let id = u32::from_be_bytes(message[1..FRAMEHEADER].try_into().unwrap());
+        let payload = &message[FRAMEHEADER..];
+        let mut table = streams.lock().unwrap();
+        match kind {
+            OPEN => {
+                //The peer must use its own parity and a new identifier
+                let ours = |id: u32| match role {
+                    Role::Client => id % 2 == 1,
+                    Role::Server => id % 2 == 0,
+                };
+                let credit = payload.try_into().map(u32::from_be_bytes).unwrap_or(u32::MAX);
+                let opened = table.keys().filter(|id| !ours(**id)).count();
+                if ours(id) || id == 0 || table.contains_key(&id) || credit > MAXWINDOW || opened >= config.maxstreams {
+                    drop(table);
+                    let _ = send(&commands, frame(RESET, id, &[]));
+                    continue;
+                }
+                drop(table);
+                let stream = MuxStream::new(id, commands.clone(), streams.clone(), config.window, credit);
+                let _ = send(&commands, frame(WINDOW, id, &config.window.to_be_bytes()));
+                let _ = accept.send(stream);
+            }
+            DATA => {
+                let Some(entry) = table.get_mut(&id) else {
+                    //Frames might still arrive for a stream dropped here
+                    continue;
+                };
+                if payload.len() as u64 > entry.remaining {
+                    reset(entry, KyberauthError::StreamReset("Peer exceeded the stream window"));
+                    drop(table);
+                    let _ = send(&commands, frame(RESET, id, &[]));
+                    continue;
+                }
+                entry.remaining -= payload.len() as u64;
+                if let Some(inbound) = &entry.inbound {
+                    let _ = inbound.send(Ok(payload.to_vec()));
+                }
+            }
+            WINDOW => {
+                let Some(entry) = table.get_mut(&id) else {
+                    continue;
+                };
+                //The credit of a stream never exceeds the largest window, so it fits in the semaphore
+                let size = payload.try_into().map(u32::from_be_bytes).unwrap_or(u32::MAX) as usize;
+                if entry.credit.is_closed() {
+                    continue;
+                }
+                if size > MAXWINDOW as usize - entry.credit.available_permits().min(MAXWINDOW as usize) {
+                    reset(entry, KyberauthError::StreamReset("Peer exceeded the stream credit"));
+                    drop(table);
+                    let _ = send(&commands, frame(RESET, id, &[]));
+                    continue;
+                }
+                entry.credit.add_permits(size);
+            }
+            CLOSE => {
+                if let Some(entry) = table.get_mut(&id) {
+                    entry.inbound = None;
+                }
+            }
+            RESET => {
+                if let Some(entry) = table.get_mut(&id) {
+                    reset(entry, KyberauthError::StreamReset("Stream reset by the peer"));
+                }
+            }
+            _ => {}
+        }
+    };
+    //Streams still open end with the error of the connection, an io::Error is shared as the source of their copies
+    let error = match error {
+        KyberauthError::Io(e) => Err(Arc::new(e)),
+        e => Ok(e),
+    };
+    for entry in streams.lock().unwrap().values_mut() {
+        let error = match &error {
+            Ok(e) => e.duplicate(),
+            Err(e) => KyberauthError::Io(io::Error::new(e.kind(), Arc::clone(e))),
+        };
+        reset(entry, error);
+    }
+}
+/// Abort a stream locally, the reader gets the error and the writer cannot send anymore.
+fn reset(entry: &mut Entry, error: KyberauthError) {
+    if let Some(inbound) = entry.inbound.take() {
+        let _ = inbound.send(Err(error));
+    }
+    entry.credit.close();
+}
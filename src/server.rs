@@ -1,59 +1,180 @@
-use safe_pqc_kyber::*;
+use crate::aes::CipherSuite;
+use crate::early::{EarlyFlight, ReplayWindow};
+use crate::error::KyberauthError;
+use crate::hello::{self, Extension, Negotiated};
+use crate::hybrid::{Share, X25519Keypair, SHARESIZE, X25519BYTES};
+use crate::kdf::{Role, Transcript, ACCEPTED, MODEEARLY, MODEFULL, MODERESUME, RANDOMSIZE, REJECTED};
+use crate::key::{self, Kem, KemKeys};
+use crate::ticket::{self, TicketKeys};
+use rand::RngCore;
+use safe_pqc_kyber::KYBER_SSBYTES;
 use sha3::{Digest, Sha3_256};
 use std::fs;
-use std::{
-    io::{self, Error, ErrorKind},
-    net::SocketAddr,
-};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
-async fn keyhandshake(socket: &mut TcpStream, key: &Keypair) -> io::Result<Vec<u8>> {
+/// Options of the server side of the handshake
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    suites: Vec<CipherSuite>,
+    tickets: Option<Arc<TicketKeys>>,
+    earlydata: Option<Arc<ReplayWindow>>,
+    extensions: Vec<Extension>,
+    hybrid: Option<X25519Keypair>,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            suites: CipherSuite::ALL.to_vec(),
+            tickets: None,
+            earlydata: None,
+            extensions: Vec::new(),
+            hybrid: None,
+        }
+    }
+}
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the cipher suites accepted from clients. The first suite of the client offer that is accepted is chosen.
+    pub fn suites(mut self, suites: &[CipherSuite]) -> Self {
+        self.suites = suites.to_vec();
+        self
+    }
+    pub fn getsuites(&self) -> &[CipherSuite] {
+        &self.suites
+    }
+    /// Accept resumption tickets encrypted with these keys, tickets are rejected without them.
+    /// The same keys are given to [`crate::aes::Connection::sendticket`] to issue tickets.
+    pub fn tickets(mut self, keys: Arc<TicketKeys>) -> Self {
+        self.tickets = Some(keys);
+        self
+    }
+    pub fn gettickets(&self) -> Option<&Arc<TicketKeys>> {
+        self.tickets.as_ref()
+    }
+    /// Accept early data from clients, replays are detected with the window. Early data is rejected without it,
+    /// the handshake still completes.
+    pub fn earlydata(mut self, window: Arc<ReplayWindow>) -> Self {
+        self.earlydata = Some(window);
+        self
+    }
+    pub fn getearlydata(&self) -> Option<&Arc<ReplayWindow>> {
+        self.earlydata.as_ref()
+    }
+    /// Set the extensions sent in the hello of the server, see [`crate::hello`].
+    pub fn extensions(mut self, extensions: &[Extension]) -> Self {
+        self.extensions = extensions.to_vec();
+        self
+    }
+    pub fn getextensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+    /// Accept the hybrid exchange with this X25519 key, see [`crate::hybrid`]. Clients using Kyber alone are still accepted.
+    pub fn hybrid(mut self, keys: X25519Keypair) -> Self {
+        self.hybrid = Some(keys);
+        self
+    }
+    pub fn gethybrid(&self) -> Option<&X25519Keypair> {
+        self.hybrid.as_ref()
+    }
+}
+/// Exchange the keys, followed by the X25519 keys of each side in the hybrid mode.
+async fn keyhandshake(
+    socket: &mut TcpStream,
+    key: &dyn KemKeys,
+    share: Option<&Share>,
+    transcript: &mut Transcript,
+) -> Result<Vec<u8>, KyberauthError> {
     let _ = socket.set_nodelay(true);
     socket.readable().await?;
-    let mut pubkey: Vec<u8> = Vec::with_capacity(KYBER_PUBLICKEYBYTES);
-    pubkey.clear();
-    pubkey.resize(KYBER_PUBLICKEYBYTES, 0);
+    let mut pubkey: Vec<u8> = vec![0; key.getkem().getpublicsize() + share.map_or(0, |_| SHARESIZE)];
     let _ = socket.read_exact(&mut pubkey).await?;
     //The key was read
+    let mut message = key.getpublic().to_vec();
+    if let Some(share) = share {
+        message.extend_from_slice(share.getmessage());
+    }
     socket.writable().await?;
-    socket.write_all(&key.public).await?;
+    socket.write_all(&message).await?;
     socket.flush().await?;
+    transcript.update([MODEFULL]);
+    transcript.update(&pubkey);
+    transcript.update(&message);
     Ok(pubkey)
 }
+/// Read the hello of the client and answer with the chosen version and parameter set `kem` of the server key, or 0 if
+/// none is supported, and the extensions.
+async fn exchangehello(
+    socket: &mut TcpStream,
+    extensions: &[Extension],
+    kem: Kem,
+    hybrid: bool,
+    transcript: &mut Transcript,
+) -> Result<Negotiated, KyberauthError> {
+    let clienthello = hello::readclienthello(socket, kem, hybrid).await?;
+    let serverhello = hello::serverhello(clienthello.version.unwrap_or(0), clienthello.kem.unwrap_or(0), extensions)?;
+    socket.writable().await?;
+    socket.write_all(&serverhello).await?;
+    socket.flush().await?;
+    transcript.update(&clienthello.raw);
+    transcript.update(&serverhello);
+    let id = clienthello.kem.ok_or(KyberauthError::NoCommonKem)?;
+    Ok(Negotiated {
+        version: clienthello.version.ok_or(KyberauthError::NoCommonVersion)?,
+        kem,
+        hybrid: id == hello::kemid(kem, true),
+        extensions: clienthello.extensions,
+    })
+}
+/// Read the cipher suites offered by the client and answer with the chosen one, or 0 if none is accepted.
+async fn negotiatesuite(
+    socket: &mut TcpStream,
+    suites: &[CipherSuite],
+    transcript: &mut Transcript,
+) -> Result<CipherSuite, KyberauthError> {
+    socket.readable().await?;
+    let mut count = [0u8; 1];
+    socket.read_exact(&mut count).await?;
+    let mut offer: Vec<u8> = vec![0; 1 + count[0] as usize];
+    offer[0] = count[0];
+    socket.read_exact(&mut offer[1..]).await?;
+    let choice = offer[1..]
+        .iter()
+        .filter_map(|id| CipherSuite::fromid(*id))
+        .find(|suite| suites.contains(suite));
+    let answer = [choice.map_or(0, |suite| suite.getid())];
+    socket.writable().await?;
+    socket.write_all(&answer).await?;
+    socket.flush().await?;
+    transcript.update(&offer);
+    transcript.update(answer);
+    choice.ok_or(KyberauthError::NoCommonSuite)
+}
 async fn checkkeys<T>(
     socket: &mut TcpStream,
-    key: &Keypair,
+    key: &dyn KemKeys,
     pubkey: T,
-) -> io::Result<[u8; KYBER_SSBYTES]>
+    transcript: &mut Transcript,
+) -> Result<[u8; KYBER_SSBYTES], KyberauthError>
 where
     T: AsRef<[u8]>,
 {
-    let pubkey = pubkey.as_ref();
+    let kem = key.getkem();
     let _ = socket.set_nodelay(true);
     socket.readable().await?;
-    let mut bob = Ake::new();
-    let mut client_init: Vec<u8> = Vec::with_capacity(AKE_INIT_BYTES);
-    client_init.clear();
-    client_init.resize(AKE_INIT_BYTES, 0);
+    let mut client_init: Vec<u8> = vec![0; kem.getinitsize()];
     let _ = socket.read_exact(&mut client_init).await?;
     //The key was read
-    if pubkey.len() != KYBER_PUBLICKEYBYTES {
-        return Err(io::Error::from(ErrorKind::InvalidInput));
-    }
-    let pubkey: [u8; KYBER_PUBLICKEYBYTES] = pubkey[..KYBER_PUBLICKEYBYTES].try_into().unwrap();
-    if client_init.len() != AKE_INIT_BYTES {
-        return Err(io::Error::from(ErrorKind::InvalidInput));
-    }
-    let client_init: [u8; AKE_INIT_BYTES] = client_init[..AKE_INIT_BYTES].try_into().unwrap();
-    let mut rng = rand::thread_rng();
-    let server_send = bob.server_receive(client_init, &pubkey, &key.secret, &mut rng);
-    if server_send.is_err() {
-        return Err(io::Error::from(ErrorKind::InvalidInput));
-    }
+    let (server_send, sharedsecret) = key::akeserver(kem, &client_init, pubkey.as_ref(), key.getsecret())?;
     socket.writable().await?;
-    socket.write_all(&server_send.unwrap()).await?;
+    socket.write_all(&server_send).await?;
     socket.flush().await?;
-    Ok(bob.shared_secret)
+    transcript.update(client_init);
+    transcript.update(server_send);
+    Ok(sharedsecret)
 }
 /// Verify peer key is allowed in authorized_keys
 pub fn verifypubkey<T>(pubkey: T) -> bool
@@ -82,7 +203,7 @@ where
     false
 }
 /// Start to listen to the socket addr
-pub async fn startlistener(addr: SocketAddr) -> io::Result<TcpListener> {
+pub async fn startlistener(addr: SocketAddr) -> Result<TcpListener, KyberauthError> {
     let socket = TcpSocket::new_v4()?;
     if cfg!(unix) {
         socket.set_reuseport(false)?;
@@ -92,25 +213,182 @@ pub async fn startlistener(addr: SocketAddr) -> io::Result<TcpListener> {
     let listener = socket.listen(1024)?;
     Ok(listener)
 }
-/// Accept incoming connection, check pub key and generate an encrypted channel
+/// Accept incoming connection, check pub key and generate an encrypted channel. The key is a `safe_pqc_kyber::Keypair`
+/// or a [`crate::key::KemKeypair`], only clients with a key of the same parameter set are accepted.
 pub async fn listener(
-    key: &Keypair,
+    key: &dyn KemKeys,
+    listener: TcpListener,
+    forceyes: bool,
+) -> Result<crate::aes::Connection, KyberauthError> {
+    listenerwith(key, listener, forceyes, &ServerConfig::default()).await
+}
+/// Same as `listener` with the given configuration
+pub async fn listenerwith(
+    key: &dyn KemKeys,
     listener: TcpListener,
     forceyes: bool,
-) -> io::Result<crate::aes::Connection> {
+    config: &ServerConfig,
+) -> Result<crate::aes::Connection, KyberauthError> {
     let (mut socket, _) = listener.accept().await?;
-    let peer_addr = socket.peer_addr();
-    if peer_addr.is_err() {
-        return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
+    let peer_addr = socket.peer_addr()?;
+    socket.readable().await?;
+    let mut transcript = Transcript::new();
+    let negotiated = exchangehello(
+        &mut socket,
+        &config.extensions,
+        key.getkem(),
+        config.hybrid.is_some(),
+        &mut transcript,
+    )
+    .await?;
+    let mut mode = [0u8; 1];
+    socket.read_exact(&mut mode).await?;
+    let share = config.hybrid.as_ref().filter(|_| negotiated.hybrid).map(Share::new);
+    let mut elem = match mode[0] {
+        MODEFULL => full(socket, peer_addr, key, forceyes, config, transcript, share).await?,
+        MODERESUME => resume(socket, peer_addr, key.getkem(), forceyes, config, transcript).await?,
+        MODEEARLY if negotiated.hybrid => return Err(KyberauthError::Handshake("No early data in the hybrid mode")),
+        MODEEARLY => early(socket, peer_addr, key, forceyes, config, transcript).await?,
+        _ => return Err(KyberauthError::Handshake("Unknown handshake mode")),
+    };
+    elem.negotiated = negotiated;
+    Ok(elem)
+}
+/// Run the full handshake, the handshake mode is already read. The X25519 keys are given in the hybrid mode.
+async fn full(
+    mut socket: TcpStream,
+    peer_addr: SocketAddr,
+    key: &dyn KemKeys,
+    forceyes: bool,
+    config: &ServerConfig,
+    mut transcript: Transcript,
+    share: Option<Share>,
+) -> Result<crate::aes::Connection, KyberauthError> {
+    let size = key.getkem().getpublicsize();
+    let peer = keyhandshake(&mut socket, key, share.as_ref(), &mut transcript).await?;
+    //The key of the client is its Kyber key, followed by its static X25519 key in the hybrid mode
+    let pubkey = &peer[..size + share.as_ref().map_or(0, |_| X25519BYTES)];
+    if !forceyes && !verifypubkey(pubkey) {
+        socket.shutdown().await?;
+        return Err(KyberauthError::UnauthorizedPeer);
+    }
+    let hexpub = hex::encode(pubkey);
+    let suite = negotiatesuite(&mut socket, &config.suites, &mut transcript).await?;
+    let mut sharedsecret = checkkeys(&mut socket, key, &peer[..size], &mut transcript).await?;
+    if let Some(share) = &share {
+        sharedsecret = share.combine(Role::Server, &peer[size..], &sharedsecret, &transcript.hash())?;
     }
-    let peer_addr = peer_addr.unwrap();
-    let pubkey = keyhandshake(&mut socket, key).await?;
+    let elem = crate::aes::Connection::new(
+        socket,
+        peer_addr,
+        hexpub,
+        sharedsecret,
+        transcript.hash(),
+        Role::Server,
+        suite,
+    );
+    Ok(elem)
+}
+/// Resume a session from the ticket sent by the client, the handshake mode is already read. The ticket holds a client
+/// key of the parameter set `kem` of the server key.
+async fn resume(
+    mut socket: TcpStream,
+    peer_addr: SocketAddr,
+    kem: Kem,
+    forceyes: bool,
+    config: &ServerConfig,
+    mut transcript: Transcript,
+) -> Result<crate::aes::Connection, KyberauthError> {
+    let mut length = [0u8; 2];
+    socket.read_exact(&mut length).await?;
+    let length = u16::from_be_bytes(length) as usize;
+    if length != ticket::ticketsize(kem) {
+        return Err(KyberauthError::Handshake("Invalid ticket size"));
+    }
+    let mut sealed: Vec<u8> = vec![0; length];
+    socket.read_exact(&mut sealed).await?;
+    let mut random = [0u8; RANDOMSIZE];
+    socket.read_exact(&mut random).await?;
+    let resumption = config
+        .tickets
+        .as_ref()
+        .and_then(|keys| ticket::accept(keys, &sealed))
+        .filter(|resumption| config.suites.contains(&resumption.suite));
+    let error = match &resumption {
+        None => Some(KyberauthError::TicketRejected),
+        Some(resumption) if !forceyes && !verifypubkey(&resumption.clientkey) => Some(KyberauthError::UnauthorizedPeer),
+        Some(_) => None,
+    };
+    if let Some(error) = error {
+        socket.write_all(&[REJECTED]).await?;
+        socket.shutdown().await?;
+        return Err(error);
+    }
+    let resumption = resumption.unwrap();
+    let mut serverrandom = [0u8; RANDOMSIZE];
+    rand::thread_rng().fill_bytes(&mut serverrandom);
+    let mut answer = [0u8; 1 + RANDOMSIZE];
+    answer[0] = ACCEPTED;
+    answer[1..].copy_from_slice(&serverrandom);
+    socket.writable().await?;
+    socket.write_all(&answer).await?;
+    socket.flush().await?;
+    transcript.update([MODERESUME]);
+    transcript.update(&sealed);
+    transcript.update(random);
+    transcript.update(serverrandom);
+    let elem = crate::aes::Connection::new(
+        socket,
+        peer_addr,
+        hex::encode(&resumption.clientkey),
+        resumption.secret,
+        transcript.hash(),
+        Role::Server,
+        resumption.suite,
+    );
+    Ok(elem)
+}
+/// Run the handshake of a client sending early data, the handshake mode is already read. The client knows the key
+/// of the server and sends its whole first flight at once. The handshake completes even if the early data is rejected.
+async fn early(
+    mut socket: TcpStream,
+    peer_addr: SocketAddr,
+    key: &dyn KemKeys,
+    forceyes: bool,
+    config: &ServerConfig,
+    mut transcript: Transcript,
+) -> Result<crate::aes::Connection, KyberauthError> {
+    let _ = socket.set_nodelay(true);
+    let mut pubkey: Vec<u8> = vec![0; key.getkem().getpublicsize()];
+    socket.read_exact(&mut pubkey).await?;
     if !forceyes && !verifypubkey(&pubkey) {
         socket.shutdown().await?;
-        return Err(Error::new(ErrorKind::InvalidData, "Key not found"));
+        return Err(KyberauthError::UnauthorizedPeer);
     }
-    let hexpub = hex::encode(pubkey.clone());
-    let sharedsecret = checkkeys(&mut socket, key, &pubkey).await?;
-    let elem = crate::aes::Connection::new(socket, peer_addr, hexpub, sharedsecret);
+    transcript.update([MODEEARLY]);
+    transcript.update(&pubkey);
+    transcript.update(key.getpublic());
+    let suite = negotiatesuite(&mut socket, &config.suites, &mut transcript).await?;
+    let sharedsecret = checkkeys(&mut socket, key, &pubkey, &mut transcript).await?;
+    let flight = EarlyFlight::read(&mut socket, key.getkem()).await?;
+    let earlydata = flight.open(key, &pubkey, &config.suites, config.earlydata.as_deref());
+    let status = [if earlydata.is_some() { ACCEPTED } else { REJECTED }];
+    socket.writable().await?;
+    socket.write_all(&status).await?;
+    socket.flush().await?;
+    flight.update(&mut transcript);
+    transcript.update(status);
+    let mut elem = crate::aes::Connection::new(
+        socket,
+        peer_addr,
+        hex::encode(&pubkey),
+        sharedsecret,
+        transcript.hash(),
+        Role::Server,
+        suite,
+    );
+    //The early keys do not authenticate the client, its heartbeat encrypted with the traffic keys does
+    elem.readheartbeat().await?;
+    elem.early = earlydata;
     Ok(elem)
 }
@@ -0,0 +1,294 @@
+use crate::aes::{Connection, KeepalivePolicy, PaddingPolicy, RecvState, Received, RekeyPolicy, SendState};
+use crate::error::KyberauthError;
+use crate::hello::{Extension, Negotiated};
+use crate::kdf::{Exporter, Role};
+use crate::key::Kem;
+use crate::ticket::{self, Ticket};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::tcp::{self, OwnedReadHalf, OwnedWriteHalf};
+/// Receiving half of a connection, created by [`Connection::into_split`].
+#[derive(Debug)]
+pub struct EncryptedReader {
+    socket: OwnedReadHalf,
+    pub peer_addr: SocketAddr,
+    pub pubkey: String,
+    role: Role,
+    exporter: Exporter,
+    //Parameter set of the keys, needed to read tickets, set after new
+    pub(crate) kem: Kem,
+    ticket: Option<Ticket>,
+    rx: RecvState,
+}
+/// Sending half of a connection, created by [`Connection::into_split`].
+#[derive(Debug)]
+pub struct EncryptedWriter {
+    socket: OwnedWriteHalf,
+    pub peer_addr: SocketAddr,
+    pub pubkey: String,
+    exporter: Exporter,
+    negotiated: Negotiated,
+    tx: SendState,
+}
+/// Error returned by `reunite` when both halves do not come from the same connection. The halves are given back.
+#[derive(Debug)]
+pub struct ReuniteError(pub Box<EncryptedReader>, pub Box<EncryptedWriter>);
+impl fmt::Display for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tried to reunite halves that are not from the same connection")
+    }
+}
+impl std::error::Error for ReuniteError {}
+impl EncryptedReader {
+    pub(crate) fn new(
+        socket: OwnedReadHalf,
+        peer_addr: SocketAddr,
+        pubkey: String,
+        role: Role,
+        exporter: Exporter,
+        ticket: Option<Ticket>,
+        rx: RecvState,
+    ) -> Self {
+        EncryptedReader {
+            socket,
+            peer_addr,
+            pubkey,
+            role,
+            exporter,
+            kem: Kem::CURRENT,
+            ticket,
+            rx,
+        }
+    }
+    /// Put back together the halves of a connection, fails if they do not come from the same connection.
+    pub fn reunite(self, writer: EncryptedWriter) -> Result<Connection, ReuniteError> {
+        match self.socket.reunite(writer.socket) {
+            Ok(socket) => Ok(Connection {
+                socket,
+                peer_addr: self.peer_addr,
+                pubkey: self.pubkey,
+                role: self.role,
+                exporter: self.exporter,
+                ticket: self.ticket,
+                early: None,
+                negotiated: writer.negotiated,
+                tx: writer.tx,
+                rx: self.rx,
+            }),
+            Err(tcp::ReuniteError(reader, socket)) => Err(ReuniteError(
+                Box::new(EncryptedReader {
+                    socket: reader,
+                    ..self
+                }),
+                Box::new(EncryptedWriter::new(
+                    socket,
+                    writer.peer_addr,
+                    writer.pubkey,
+                    writer.exporter,
+                    writer.negotiated,
+                    writer.tx,
+                )),
+            )),
+        }
+    }
+    /// Set the maximum size of a message that can be received. Bigger messages return an error.
+    pub fn setmaxsize(&mut self, maxsize: usize) {
+        self.rx.maxsize = maxsize;
+    }
+    pub fn getmaxsize(&self) -> usize {
+        self.rx.maxsize
+    }
+    /// Set the deadlines used to receive data, the reader never sends heartbeats.
+    pub fn setkeepalivepolicy(&mut self, policy: KeepalivePolicy) {
+        self.rx.keepalive = policy;
+    }
+    pub fn getkeepalivepolicy(&self) -> KeepalivePolicy {
+        self.rx.keepalive
+    }
+    /// Get the number of times the key used to receive data was updated
+    pub fn getreceiveepoch(&self) -> u64 {
+        self.rx.epoch
+    }
+    /// Get the sequence number expected for the next record to be received
+    pub fn getreceivesequence(&self) -> u64 {
+        self.rx.seq
+    }
+    pub fn getpeer(&self) -> SocketAddr {
+        self.peer_addr
+    }
+    /// Derive keying material bound to the connection, see [`Connection::export_keying_material`].
+    pub fn export_keying_material<L, C>(&self, label: L, context: C, len: usize) -> Result<Vec<u8>, KyberauthError>
+    where
+        L: AsRef<[u8]>,
+        C: AsRef<[u8]>,
+    {
+        self.exporter.export(label.as_ref(), context.as_ref(), len)
+    }
+    /// Receive one encrypted message and decrypt it, see [`Connection::receivedata`].
+    pub async fn receivedata(&mut self) -> Result<Option<Vec<u8>>, KyberauthError> {
+        self.receivedata_with_aad([]).await
+    }
+    /// Same as `receivedata`, fails if the associated data does not match the one given by the peer to `senddata_with_aad`.
+    pub async fn receivedata_with_aad<A>(&mut self, aad: A) -> Result<Option<Vec<u8>>, KyberauthError> where A: AsRef<[u8]> {
+        let mut message: Vec<u8> = Vec::new();
+        Ok(self.receiveinto(aad.as_ref(), &mut message).await?.map(|_| message))
+    }
+    /// Receive one message in the buffer, see [`Connection::receive_into`].
+    pub async fn receive_into(&mut self, buffer: &mut Vec<u8>) -> Result<Option<usize>, KyberauthError> {
+        self.receiveinto(&[], buffer).await
+    }
+    async fn receiveinto(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> Result<Option<usize>, KyberauthError> {
+        loop {
+            match self.rx.next(&mut self.socket, aad, buffer).await? {
+                Received::Message => return Ok(Some(buffer.len())),
+                Received::Close => return Ok(None),
+                Received::Heartbeat => {}
+                Received::Ticket => {
+                    self.ticket = Some(ticket::receive(self.role, buffer, &self.exporter, self.rx.suite, self.kem, &self.pubkey)?);
+                    buffer.clear();
+                }
+            }
+        }
+    }
+    /// Take the last ticket received from the server, see [`Connection::taketicket`].
+    pub fn taketicket(&mut self) -> Option<Ticket> {
+        self.ticket.take()
+    }
+    /// Decrypt data produced by `encryptdata` on the peer without reading from the socket, see [`Connection::decryptdata`].
+    pub fn decryptdata<T>(&mut self, input: T) -> Result<Vec<u8>, KyberauthError> where T: AsRef<[u8]> {
+        self.decryptdata_with_aad(input, [])
+    }
+    /// Same as `decryptdata`, fails if the associated data does not match the one given to `encryptdata_with_aad`.
+    pub fn decryptdata_with_aad<T, A>(&mut self, input: T, aad: A) -> Result<Vec<u8>, KyberauthError>
+    where
+        T: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
+        Ok(self.rx.decrypt(input.as_ref(), aad.as_ref())?)
+    }
+    /// Decrypt the records in the buffer in place, see [`Connection::decrypt_in_place`].
+    pub fn decrypt_in_place(&mut self, buffer: &mut Vec<u8>) -> Result<(), KyberauthError> {
+        Ok(self.rx.decryptinplace(buffer, &[])?)
+    }
+}
+impl EncryptedWriter {
+    pub(crate) fn new(
+        socket: OwnedWriteHalf,
+        peer_addr: SocketAddr,
+        pubkey: String,
+        exporter: Exporter,
+        negotiated: Negotiated,
+        tx: SendState,
+    ) -> Self {
+        EncryptedWriter {
+            socket,
+            peer_addr,
+            pubkey,
+            exporter,
+            negotiated,
+            tx,
+        }
+    }
+    /// Send an authenticated close notify then flush and shutdown the sending side of the socket.
+    /// The reader can still receive data.
+    pub async fn clean(&mut self) -> Result<(), KyberauthError> {
+        Ok(self.tx.close(&mut self.socket).await?)
+    }
+    /// Send a heartbeat record, see [`Connection::sendheartbeat`].
+    pub async fn sendheartbeat(&mut self) -> Result<(), KyberauthError> {
+        Ok(self.tx.heartbeat(&mut self.socket).await?)
+    }
+    /// Send a single record of a message.
+    pub(crate) async fn sendrecord(&mut self, index: u32, flags: u8, data: &[u8]) -> io::Result<()> {
+        self.tx.sendrecord(&mut self.socket, index, flags, data, &[]).await
+    }
+    /// Set the maximum size of a message that can be sent. Bigger messages return an error.
+    pub fn setmaxsize(&mut self, maxsize: usize) {
+        self.tx.maxsize = maxsize;
+    }
+    pub fn getmaxsize(&self) -> usize {
+        self.tx.maxsize
+    }
+    /// Set when the key used to send data is updated. The peer does not need the same policy.
+    pub fn setrekeypolicy(&mut self, policy: RekeyPolicy) {
+        self.tx.rekey = policy;
+    }
+    pub fn getrekeypolicy(&self) -> RekeyPolicy {
+        self.tx.rekey
+    }
+    /// Set how the records sent are padded, see [`Connection::setpaddingpolicy`].
+    pub fn setpaddingpolicy(&mut self, policy: PaddingPolicy) {
+        self.tx.padding = policy;
+    }
+    pub fn getpaddingpolicy(&self) -> PaddingPolicy {
+        self.tx.padding
+    }
+    /// Set the deadline used to send data. Heartbeats are not sent automatically, use `sendheartbeat`.
+    pub fn setkeepalivepolicy(&mut self, policy: KeepalivePolicy) {
+        self.tx.keepalive = policy;
+    }
+    pub fn getkeepalivepolicy(&self) -> KeepalivePolicy {
+        self.tx.keepalive
+    }
+    /// Get the number of times the key used to send data was updated
+    pub fn getsendepoch(&self) -> u64 {
+        self.tx.epoch
+    }
+    /// Get the sequence number of the next record to be sent
+    pub fn getsendsequence(&self) -> u64 {
+        self.tx.seq
+    }
+    pub fn getpeer(&self) -> SocketAddr {
+        self.peer_addr
+    }
+    /// Get the protocol version chosen by the server during the handshake
+    pub fn getversion(&self) -> u16 {
+        self.negotiated.version
+    }
+    /// Get the extensions sent by the peer in its hello, see [`Connection::getpeerextensions`].
+    pub fn getpeerextensions(&self) -> &[Extension] {
+        &self.negotiated.extensions
+    }
+    /// Derive keying material bound to the connection, see [`Connection::export_keying_material`].
+    pub fn export_keying_material<L, C>(&self, label: L, context: C, len: usize) -> Result<Vec<u8>, KyberauthError>
+    where
+        L: AsRef<[u8]>,
+        C: AsRef<[u8]>,
+    {
+        self.exporter.export(label.as_ref(), context.as_ref(), len)
+    }
+    /// Encrypt data and send it, see [`Connection::senddata`].
+    pub async fn senddata<T>(&mut self, text: T) -> Result<(), KyberauthError> where T: AsRef<[u8]> {
+        self.senddata_with_aad(text, []).await
+    }
+    /// Same as `senddata`, the associated data is authenticated with the message but not sent.
+    pub async fn senddata_with_aad<T, A>(&mut self, text: T, aad: A) -> Result<(), KyberauthError>
+    where
+        T: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
+        self.socket.writable().await?;
+        Ok(self.tx.send(&mut self.socket, text.as_ref(), aad.as_ref()).await?)
+    }
+    /// Encrypt data without sending to the socket, see [`Connection::encryptdata`].
+    pub fn encryptdata<T>(&mut self, input: T) -> Result<Vec<u8>, KyberauthError> where T: AsRef<[u8]> {
+        self.encryptdata_with_aad(input, [])
+    }
+    /// Same as `encryptdata`, the associated data is authenticated with the message but not included in the output.
+    pub fn encryptdata_with_aad<T, A>(&mut self, input: T, aad: A) -> Result<Vec<u8>, KyberauthError>
+    where
+        T: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+    {
+        self.tx.encrypt(input.as_ref(), aad.as_ref())
+    }
+    /// Encrypt the message in the buffer in place, see [`Connection::encrypt_in_place`].
+    pub fn encrypt_in_place(&mut self, buffer: &mut Vec<u8>) -> Result<(), KyberauthError> {
+        self.tx.encryptinplace(buffer, &[])
+    }
+    /// Get the size of the records of a message, see [`Connection::encryptedsize`].
+    pub fn encryptedsize(&self, size: usize) -> usize {
+        self.tx.encryptedsize(size)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::aes::{Connection, CHUNKSIZE, FLAG_CLOSE, FLAG_FINAL, FLAG_TICKET, HEADERSIZE, RECORDSIZE};
+use crate::error::KyberauthError;
+use crate::ticket;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+/// Byte stream over an encrypted connection, implementing `AsyncRead` and `AsyncWrite`.
+/// Written bytes are buffered and sent as one record when the buffer is full or on flush.
+/// Records sent by `senddata` on the other side can be read as well.
+/// The end of stream is only reported after the close notify of the peer, sent by `shutdown` or `clean`.
+#[derive(Debug)]
+pub struct EncryptedStream {
+    connection: Connection,
+    //Record being read, with its length header
+    readbuf: Vec<u8>,
+    readfilled: usize,
+    readindex: u32,
+    //Decrypted data not yet returned to the reader
+    plaintext: Vec<u8>,
+    plainpos: usize,
+    //Data waiting to be sealed in a record
+    writebuf: Vec<u8>,
+    //Sealed record being written to the socket
+    pending: Vec<u8>,
+    pendingpos: usize,
+}
+impl EncryptedStream {
+    pub fn new(connection: Connection) -> Self {
+        EncryptedStream {
+            connection,
+            readbuf: vec![0; HEADERSIZE],
+            readfilled: 0,
+            readindex: 0,
+            plaintext: Vec::new(),
+            plainpos: 0,
+            writebuf: Vec::with_capacity(CHUNKSIZE),
+            pending: Vec::new(),
+            pendingpos: 0,
+        }
+    }
+    pub fn getconnection(&self) -> &Connection {
+        &self.connection
+    }
+    /// Get back the connection. Buffered data that was not flushed or read is lost.
+    pub fn intoconnection(self) -> Connection {
+        self.connection
+    }
+    /// Read from the socket until `readbuf` is filled. The end of stream is an error, the peer must send a close notify.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.readfilled < self.readbuf.len() {
+            let mut buf = ReadBuf::new(&mut self.readbuf[self.readfilled..]);
+            ready!(Pin::new(&mut self.connection.socket).poll_read(cx, &mut buf))?;
+            let read = buf.filled().len();
+            if read == 0 {
+                return Poll::Ready(Err(KyberauthError::Truncated("Connection closed without close notify").into()));
+            }
+            self.readfilled += read;
+        }
+        Poll::Ready(Ok(()))
+    }
+    /// Read and decrypt the next record. Returns false after the close notify of the peer.
+    fn poll_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        if self.connection.rx.closed {
+            return Poll::Ready(Ok(false));
+        }
+        if self.readbuf.len() == HEADERSIZE {
+            ready!(self.poll_fill(cx))?;
+            let size = u32::from_be_bytes(self.readbuf[..HEADERSIZE].try_into().unwrap()) as usize;
+            if size > RECORDSIZE {
+                return Poll::Ready(Err(KyberauthError::InvalidRecord("Record too large").into()));
+            }
+            self.readbuf.resize(HEADERSIZE + size, 0);
+        }
+        ready!(self.poll_fill(cx))?;
+        let record = self.connection.rx.open(self.readindex, &mut self.readbuf[HEADERSIZE..], &[]);
+        self.readfilled = 0;
+        let (flags, data) = match record {
+            Ok(record) => record,
+            Err(e) => {
+                self.readbuf.truncate(HEADERSIZE);
+                return Poll::Ready(Err(e));
+            }
+        };
+        //The memory of both buffers is reused for the next records
+        self.plaintext.clear();
+        self.plaintext.extend_from_slice(&self.readbuf[HEADERSIZE + data.start..HEADERSIZE + data.end]);
+        self.plainpos = 0;
+        self.readbuf.truncate(HEADERSIZE);
+        if flags & FLAG_CLOSE != 0 {
+            if self.readindex != 0 {
+                return Poll::Ready(Err(KyberauthError::Truncated("Connection closed in the middle of a message").into()));
+            }
+            self.connection.rx.closed = true;
+            return Poll::Ready(Ok(false));
+        }
+        if flags & FLAG_TICKET != 0 {
+            let connection = &self.connection;
+            let ticket = ticket::receive(
+                connection.role,
+                &self.plaintext,
+                &connection.exporter,
+                connection.rx.suite,
+                connection.negotiated.kem,
+                &connection.pubkey,
+            )?;
+            self.connection.ticket = Some(ticket);
+            self.plaintext.clear();
+        }
+        if flags & FLAG_FINAL != 0 {
+            self.readindex = 0;
+        } else {
+            self.readindex = match self.readindex.checked_add(1) {
+                Some(index) => index,
+                None => return Poll::Ready(Err(KyberauthError::InvalidRecord("Too many records in a message").into())),
+            };
+        }
+        Poll::Ready(Ok(true))
+    }
+    /// Seal buffered data in a record and write it to the socket.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            while self.pendingpos < self.pending.len() {
+                let written = ready!(Pin::new(&mut self.connection.socket)
+                    .poll_write(cx, &self.pending[self.pendingpos..]))?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::Error::from(ErrorKind::WriteZero)));
+                }
+                self.pendingpos += written;
+            }
+            if self.writebuf.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            self.pending.clear();
+            self.pendingpos = 0;
+            self.connection.tx.sealinto(0, FLAG_FINAL, &self.writebuf, &[], &mut self.pending)?;
+            self.writebuf.clear();
+        }
+    }
+}
+impl AsyncRead for EncryptedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        //Empty records are skipped until some data or the end of stream is found
+        while this.plainpos == this.plaintext.len() {
+            if !ready!(this.poll_record(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let size = buf.remaining().min(this.plaintext.len() - this.plainpos);
+        buf.put_slice(&this.plaintext[this.plainpos..this.plainpos + size]);
+        this.plainpos += size;
+        Poll::Ready(Ok(()))
+    }
+}
+impl AsyncWrite for EncryptedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.connection.tx.closed {
+            return Poll::Ready(Err(KyberauthError::Closed.into()));
+        }
+        if this.writebuf.len() >= CHUNKSIZE {
+            ready!(this.poll_drain(cx))?;
+        }
+        let size = buf.len().min(CHUNKSIZE - this.writebuf.len());
+        this.writebuf.extend_from_slice(&buf[..size]);
+        Poll::Ready(Ok(size))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.connection.socket).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if !this.connection.tx.closed {
+            this.pending = this.connection.tx.sealclose()?;
+            this.pendingpos = 0;
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.connection.socket).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,300 @@
+//! Session resumption. Over an established connection, the server issues tickets: the secret of a future session
+//! encrypted with a key only the server knows. A client presenting a ticket gets fresh traffic keys, derived from the
+//! ticket secret and new random values of both sides, without any Kyber operation. A ticket can only be used with a key
+//! of the parameter set of the connection that received it.
+//!
+//! Sessions resumed from a ticket are only as safe as the ticket key: whoever gets it and a ticket can derive the keys
+//! of the sessions resumed with that ticket. Rotate the ticket key often and revoke it if it might have leaked.
+use crate::aes::CipherSuite;
+use crate::error::KyberauthError;
+use crate::kdf::{Exporter, Role};
+use crate::key::Kem;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::RngCore;
+use safe_pqc_kyber::KYBER_SSBYTES;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+const KEYSIZE: usize = 256 / 8;
+const NONCESIZE: usize = 96 / 8;
+const TAGSIZE: usize = 128 / 8;
+//Nonce sent with a ticket, the secret of the ticket is exported with it
+const SECRETNONCE: usize = 16;
+const RESUMPTIONLABEL: &[u8] = b"kyberauth resumption";
+//Issue time, lifetime, cipher suite and secret, followed by the public key of the client
+const CONTENTSIZE: usize = 8 + 4 + 1 + KYBER_SSBYTES;
+/// Size of an encrypted ticket for a client key of the parameter set: key identifier, nonce, content and tag.
+pub(crate) fn ticketsize(kem: Kem) -> usize {
+    4 + NONCESIZE + CONTENTSIZE + kem.getpublicsize() + TAGSIZE
+}
+#[derive(Zeroize, ZeroizeOnDrop)]
+struct TicketKey {
+    #[zeroize(skip)]
+    id: u32,
+    key: [u8; KEYSIZE],
+    //Set once a newer key encrypts the tickets
+    #[zeroize(skip)]
+    retired: Option<Instant>,
+}
+struct Keyring {
+    //The current key is the last one
+    keys: Vec<TicketKey>,
+    nextid: u32,
+}
+impl Keyring {
+    /// Create a random key and use it for the next tickets.
+    fn addkey(&mut self) -> u32 {
+        let now = Instant::now();
+        for key in self.keys.iter_mut().filter(|key| key.retired.is_none()) {
+            key.retired = Some(now);
+        }
+        let mut key = TicketKey {
+            id: self.nextid,
+            key: [0; KEYSIZE],
+            retired: None,
+        };
+        rand::thread_rng().fill_bytes(&mut key.key);
+        self.nextid = self.nextid.wrapping_add(1);
+        self.keys.push(key);
+        self.nextid.wrapping_sub(1)
+    }
+    fn current(&self) -> &TicketKey {
+        //There is always a current key
+        self.keys.last().unwrap()
+    }
+}
+/// Keys encrypting the tickets issued by a server, shared by every connection of the server.
+/// Tickets can be used until their lifetime expires, or until the key that encrypted them is revoked.
+pub struct TicketKeys {
+    lifetime: Duration,
+    ring: Mutex<Keyring>,
+}
+impl fmt::Debug for TicketKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TicketKeys")
+            .field("lifetime", &self.lifetime)
+            .field("current", &self.getcurrentid())
+            .finish()
+    }
+}
+impl TicketKeys {
+    /// Create a random ticket key. Tickets are valid for `lifetime` after being issued, at most `u32::MAX` seconds.
+    pub fn new(lifetime: Duration) -> Self {
+        let mut ring = Keyring {
+            keys: Vec::new(),
+            nextid: 1,
+        };
+        ring.addkey();
+        TicketKeys {
+            lifetime: lifetime.min(Duration::from_secs(u32::MAX as u64)),
+            ring: Mutex::new(ring),
+        }
+    }
+    pub fn getlifetime(&self) -> Duration {
+        self.lifetime
+    }
+    /// Get the identifier of the key encrypting new tickets
+    pub fn getcurrentid(&self) -> u32 {
+        self.ring.lock().unwrap().current().id
+    }
+    /// Encrypt the next tickets with a new random key and returns its identifier. Tickets encrypted with the previous
+    /// keys are still accepted until they expire, keys retired for longer than the lifetime are dropped.
+    pub fn rotate(&self) -> u32 {
+        let mut ring = self.ring.lock().unwrap();
+        let lifetime = self.lifetime;
+        ring.keys.retain(|key| key.retired.is_none_or(|retired| retired.elapsed() < lifetime));
+        ring.addkey()
+    }
+    /// Reject every ticket encrypted with the key. A new key is created if it was the current one.
+    /// Returns false if the key is unknown.
+    pub fn revoke(&self, id: u32) -> bool {
+        let mut ring = self.ring.lock().unwrap();
+        let Some(position) = ring.keys.iter().position(|key| key.id == id) else {
+            return false;
+        };
+        ring.keys.remove(position);
+        if ring.keys.last().is_none_or(|key| key.retired.is_some()) {
+            ring.addkey();
+        }
+        true
+    }
+    /// Reject every ticket issued so far, the next tickets use a new key.
+    pub fn revokeall(&self) {
+        let mut ring = self.ring.lock().unwrap();
+        ring.keys.clear();
+        ring.addkey();
+    }
+    /// Encrypt the content of a ticket with the current key.
+    fn seal(&self, content: &[u8]) -> Vec<u8> {
+        let ring = self.ring.lock().unwrap();
+        let key = ring.current();
+        let mut nonce = [0u8; NONCESIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let id = key.id.to_be_bytes();
+        let cipher = Aes256Gcm::new((&key.key).into());
+        //A ticket is far below the limit of the AEAD, encryption cannot fail
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: content, aad: &id })
+            .unwrap();
+        let mut ticket: Vec<u8> = Vec::with_capacity(4 + NONCESIZE + sealed.len());
+        ticket.extend_from_slice(&id);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        ticket
+    }
+    /// Decrypt a ticket, returns `None` if its key is unknown or revoked or if it was tampered with.
+    fn open(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < 4 + NONCESIZE + CONTENTSIZE + TAGSIZE {
+            return None;
+        }
+        let id: [u8; 4] = ticket[..4].try_into().unwrap();
+        let ring = self.ring.lock().unwrap();
+        let key = ring.keys.iter().find(|key| key.id == u32::from_be_bytes(id))?;
+        let cipher = Aes256Gcm::new((&key.key).into());
+        cipher
+            .decrypt(Nonce::from_slice(&ticket[4..4 + NONCESIZE]), Payload { msg: &ticket[4 + NONCESIZE..], aad: &id })
+            .ok()
+    }
+}
+/// Ticket issued by a server, used by [`crate::client::resume`] to connect again without a full handshake.
+/// It can be used several times until it expires, but sessions resumed with the same ticket can be linked together.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct Ticket {
+    ticket: Vec<u8>,
+    secret: [u8; KYBER_SSBYTES],
+    #[zeroize(skip)]
+    suite: CipherSuite,
+    #[zeroize(skip)]
+    kem: Kem,
+    pubkey: String,
+    #[zeroize(skip)]
+    expiry: SystemTime,
+}
+impl fmt::Debug for Ticket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ticket")
+            .field("suite", &self.suite)
+            .field("kem", &self.kem)
+            .field("pubkey", &self.pubkey)
+            .field("expiry", &self.expiry)
+            .finish()
+    }
+}
+impl Ticket {
+    /// Get the time after which the server rejects the ticket
+    pub fn getexpiry(&self) -> SystemTime {
+        self.expiry
+    }
+    pub fn isexpired(&self) -> bool {
+        SystemTime::now() >= self.expiry
+    }
+    /// Get the cipher suite of the sessions resumed with the ticket
+    pub fn getciphersuite(&self) -> CipherSuite {
+        self.suite
+    }
+    /// Get the parameter set of the key that can resume sessions with the ticket
+    pub fn getkem(&self) -> Kem {
+        self.kem
+    }
+    /// Get the public key of the server that issued the ticket, in hex
+    pub fn getpeerkey(&self) -> &str {
+        &self.pubkey
+    }
+    pub(crate) fn getticket(&self) -> &[u8] {
+        &self.ticket
+    }
+    pub(crate) fn getsecret(&self) -> [u8; KYBER_SSBYTES] {
+        self.secret
+    }
+}
+/// Session found in a ticket accepted by the server.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub(crate) struct Resumption {
+    pub(crate) secret: [u8; KYBER_SSBYTES],
+    #[zeroize(skip)]
+    pub(crate) suite: CipherSuite,
+    pub(crate) clientkey: Vec<u8>,
+}
+/// Build the ticket record sent by the server: the nonce giving the ticket secret, the lifetime and the encrypted ticket.
+pub(crate) fn issue(
+    keys: &TicketKeys,
+    exporter: &Exporter,
+    suite: CipherSuite,
+    kem: Kem,
+    clientkey: &[u8],
+) -> Result<Vec<u8>, KyberauthError> {
+    if clientkey.len() != kem.getpublicsize() {
+        return Err(KyberauthError::InvalidConfig("Invalid public key of the client"));
+    }
+    let mut nonce = [0u8; SECRETNONCE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut secret = exporter.export(RESUMPTIONLABEL, &nonce, KYBER_SSBYTES)?;
+    let lifetime = keys.lifetime.as_secs() as u32;
+    let issued = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let mut content: Vec<u8> = Vec::with_capacity(CONTENTSIZE + clientkey.len());
+    content.extend_from_slice(&issued.to_be_bytes());
+    content.extend_from_slice(&lifetime.to_be_bytes());
+    content.push(suite.getid());
+    content.extend_from_slice(&secret);
+    content.extend_from_slice(clientkey);
+    let ticket = keys.seal(&content);
+    content.zeroize();
+    secret.zeroize();
+    let mut record: Vec<u8> = Vec::with_capacity(SECRETNONCE + 4 + ticket.len());
+    record.extend_from_slice(&nonce);
+    record.extend_from_slice(&lifetime.to_be_bytes());
+    record.extend_from_slice(&ticket);
+    Ok(record)
+}
+/// Read a ticket record sent by the server. The secret of the ticket is exported from the current connection, whose
+/// client key has the parameter set `kem`.
+pub(crate) fn receive(
+    role: Role,
+    record: &[u8],
+    exporter: &Exporter,
+    suite: CipherSuite,
+    kem: Kem,
+    pubkey: &str,
+) -> Result<Ticket, KyberauthError> {
+    if role != Role::Client {
+        return Err(KyberauthError::InvalidRecord("Ticket sent by the client"));
+    }
+    if record.len() != SECRETNONCE + 4 + ticketsize(kem) {
+        return Err(KyberauthError::InvalidRecord("Invalid ticket"));
+    }
+    let mut secret = exporter.export(RESUMPTIONLABEL, &record[..SECRETNONCE], KYBER_SSBYTES)?;
+    let lifetime = u32::from_be_bytes(record[SECRETNONCE..SECRETNONCE + 4].try_into().unwrap());
+    let mut ticket = Ticket {
+        ticket: record[SECRETNONCE + 4..].to_vec(),
+        secret: [0; KYBER_SSBYTES],
+        suite,
+        kem,
+        pubkey: pubkey.to_string(),
+        expiry: SystemTime::now() + Duration::from_secs(lifetime as u64),
+    };
+    ticket.secret.copy_from_slice(&secret);
+    secret.zeroize();
+    Ok(ticket)
+}
+/// Decrypt a ticket presented by a client, returns `None` if it is invalid, revoked or expired.
+pub(crate) fn accept(keys: &TicketKeys, ticket: &[u8]) -> Option<Resumption> {
+    let mut content = keys.open(ticket)?;
+    let issued = u64::from_be_bytes(content[..8].try_into().unwrap());
+    let lifetime = u32::from_be_bytes(content[8..12].try_into().unwrap()) as u64;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let suite = CipherSuite::fromid(content[12]);
+    let mut resumption = None;
+    if let Some(suite) = suite.filter(|_| now < issued.saturating_add(lifetime)) {
+        let mut secret = [0u8; KYBER_SSBYTES];
+        secret.copy_from_slice(&content[13..13 + KYBER_SSBYTES]);
+        resumption = Some(Resumption {
+            secret,
+            suite,
+            clientkey: content[13 + KYBER_SSBYTES..].to_vec(),
+        });
+    }
+    content.zeroize();
+    resumption
+}